@@ -18,59 +18,174 @@
 use std::io;
 use std::fs::File;
 use std::io::{BufReader, Read};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::ffi::OsString;
 use std::error::Error;
+use std::process::Command;
 use std::os::unix::ffi::OsStrExt;
-use yafpm::{BuildCxt, BuildError};
-
-#[cfg(feature = "serde_json")]
-use serde_json;
-#[cfg(feature = "toml")]
-use toml;
+use url::Url;
+use yafpm::{build_order, BuildCxt, BuildError, BuildLimits, CheckReport, FetchOpts, Package};
+use yafpm::{CACHE_DIR, MAIN_OUTPUT, MIRRORS_FILE};
 
 const USAGE: &str =
-"Usage: yafpm-build [-hv] [-P|--package-dir=<pkg_dir>] [--toml|--json] <file>";
+"Usage: yafpm-build [-hv] [-P|--package-dir=<pkg_dir>] [-C|--cache-dir=<cache_dir>]
+       [-M|--mirrors=<file>] [--toml|--json] [--no-deps]
+       [--print-hash|--discover|--check]
+       [--timeout=<secs>] [--silence-timeout=<secs>] [--memory=<size>]
+       [--cpus=<n>] [--open-files=<n>] [--processes=<n>] [--disk=<size>]
+       [--cgroup=<dir>] [--keep-failed] [--allow-undeclared-refs] <file>";
 const PACKAGE_DIR: &str = "/yafpm";
 
+#[allow(clippy::upper_case_acronyms)]
 enum FileType {
     JSON,
     TOML,
     Unknown
 }
 
-fn parse_args() -> Result<(
-    FileType,
-    Option<OsString>,
-    Option<OsString>,
-    u8
-), lexopt::Error> {
+struct Args {
+    ft: FileType,
+    file_str: Option<OsString>,
+    pkg_dir: Option<OsString>,
+    cache_dir: Option<OsString>,
+    mirrors: Option<OsString>,
+    verbosity: u8,
+    no_deps: bool,
+    discover: Discover,
+    check: bool,
+    // Limits given on the command line, which override those of the recipe
+    limits: BuildLimits,
+    cgroup: Option<OsString>,
+    keep_failed: bool,
+    allow_undeclared_refs: bool,
+    // The arguments that pass the options above on to the builds of
+    // dependencies
+    dep_args: Vec<OsString>,
+}
+
+// Whether to build without knowing the output hash, and if so whether to keep
+// the output once its hash is known
+#[derive(Clone, Copy, PartialEq)]
+enum Discover {
+    No,
+    PrintHash,
+    Install,
+}
+
+fn parse_args() -> Result<Args, lexopt::Error> {
     use lexopt::prelude::*;
-    let mut ft = FileType::Unknown;
-    let mut file_str = None;
-    let mut pkg_dir = None;
-    let mut verbosity: u8 = 0;
+    let mut args = Args {
+        ft: FileType::Unknown,
+        file_str: None,
+        pkg_dir: None,
+        cache_dir: None,
+        mirrors: None,
+        verbosity: 0,
+        no_deps: false,
+        discover: Discover::No,
+        check: false,
+        limits: BuildLimits::new(),
+        cgroup: None,
+        keep_failed: false,
+        allow_undeclared_refs: false,
+        dep_args: Vec::new(),
+    };
 
     let mut parser = lexopt::Parser::from_env();
     while let Some(arg) = parser.next()? {
         match arg {
-            Long("json") => { ft = FileType::JSON; }
-            Long("toml") => { ft = FileType::TOML; }
-            Short('v') => { verbosity += 1;}
+            Long("json") => { args.ft = FileType::JSON; }
+            Long("toml") => { args.ft = FileType::TOML; }
+            Long("no-deps") => { args.no_deps = true; }
+            Long("print-hash") => { args.discover = Discover::PrintHash; }
+            Long("discover") => { args.discover = Discover::Install; }
+            Long("check") => { args.check = true; }
+            Short('v') => { args.verbosity += 1;}
             Short('P') | Long("package-dir") => {
-                pkg_dir = Some(parser.value()?);
+                args.pkg_dir = Some(parser.value()?);
+            }
+            Short('C') | Long("cache-dir") => {
+                args.cache_dir = Some(parser.value()?);
+            }
+            Short('M') | Long("mirrors") => {
+                args.mirrors = Some(parser.value()?);
+            }
+            Long("timeout") => {
+                let secs = parser.value()?.parse()?;
+                args.limits.set_timeout(secs);
+                args.dep_args.push(format!("--timeout={}", secs).into());
+            }
+            Long("silence-timeout") => {
+                let secs = parser.value()?.parse()?;
+                args.limits.set_silence_timeout(secs);
+                args.dep_args.push(format!("--silence-timeout={}", secs).into());
+            }
+            Long("memory") => {
+                let bytes = parser.value()?.parse_with(parse_size)?;
+                args.limits.set_memory(bytes);
+                args.dep_args.push(format!("--memory={}", bytes).into());
+            }
+            Long("cpus") => {
+                let cpus = parser.value()?.parse()?;
+                args.limits.set_cpus(cpus);
+                args.dep_args.push(format!("--cpus={}", cpus).into());
+            }
+            Long("open-files") => {
+                let files = parser.value()?.parse()?;
+                args.limits.set_open_files(files);
+                args.dep_args.push(format!("--open-files={}", files).into());
+            }
+            Long("processes") => {
+                let processes = parser.value()?.parse()?;
+                args.limits.set_processes(processes);
+                args.dep_args.push(format!("--processes={}", processes).into());
+            }
+            Long("disk") => {
+                let bytes = parser.value()?.parse_with(parse_size)?;
+                args.limits.set_disk(bytes);
+                args.dep_args.push(format!("--disk={}", bytes).into());
+            }
+            Long("cgroup") => {
+                let dir = parser.value()?;
+                let mut arg = OsString::from("--cgroup=");
+                arg.push(&dir);
+                args.dep_args.push(arg);
+                args.cgroup = Some(dir);
+            }
+            Long("keep-failed") => {
+                args.keep_failed = true;
+                args.dep_args.push("--keep-failed".into());
+            }
+            Long("allow-undeclared-refs") => {
+                args.allow_undeclared_refs = true;
+                args.dep_args.push("--allow-undeclared-refs".into());
             }
             Short('h') | Long("help") => {
                 println!("{}", USAGE);
                 std::process::exit(0);
             }
             Value(val) => {
-                file_str = Some(val);
+                args.file_str = Some(val);
             }
             _ => return Err(arg.unexpected()),
         }
     }
-    Ok((ft, file_str, pkg_dir, verbosity))
+    if args.check && args.discover != Discover::No {
+        return Err("--check can't be combined with --print-hash or --discover".into());
+    }
+    Ok(args)
+}
+
+// A number of bytes, optionally followed by K, M or G
+fn parse_size(size: &str) -> Result<u64, String> {
+    let (digits, shift) = match size.as_bytes().last() {
+        Some(b'K') | Some(b'k') => (&size[..size.len() - 1], 10),
+        Some(b'M') | Some(b'm') => (&size[..size.len() - 1], 20),
+        Some(b'G') | Some(b'g') => (&size[..size.len() - 1], 30),
+        _ => (size, 0),
+    };
+    let n: u64 = digits.parse().map_err(|e| format!("{}", e))?;
+    n.checked_mul(1 << shift).ok_or_else(|| "size too large".to_string())
 }
 
 fn read_path_to_string<P: AsRef<Path>>(file_name: P) -> Result<String, io::Error> {
@@ -96,8 +211,7 @@ fn get_config_format(ft: FileType, file_path: &Path) -> FileType {
 }
 
 // So our config file can have things like "url = './build.sh'"
-fn set_serde_base_url(file_path: &Path) -> Result<(), io::Error> {
-    use url::Url;
+fn set_serde_base_url(file_path: &Path) -> Result<Url, io::Error> {
     use yafpm::SERDE_BASE_URL;
 
     let absolute_path = match file_path.is_absolute() {
@@ -114,42 +228,212 @@ fn set_serde_base_url(file_path: &Path) -> Result<(), io::Error> {
     let url = Url::from_file_path(absolute_path).unwrap();
 
     unsafe {
-        SERDE_BASE_URL = Some(url);
+        SERDE_BASE_URL = Some(url.clone());
     }
 
-    Ok(())
+    Ok(url)
 
 }
 
-fn main() {
-    let (ft, file_str, pkg_dir, _verbosity) = match parse_args() {
-        Ok((ft, Some(file_str), pkg_dir, verbosity)) =>
-            (ft, file_str, pkg_dir, verbosity),
-        Ok((_, None, _, _)) => {
-            eprintln!("Missing command line argument: <file>");
-            eprintln!("{}", USAGE);
+fn load_dep_recipe(url: &Url) -> Result<BuildCxt<'static>, Box<dyn Error>> {
+    let path = url.to_file_path().map_err(
+        |_| "only local recipes can be used for dependencies")?;
+    // Relative URLs in this recipe are relative to it, not to the recipe
+    // that named it
+    set_serde_base_url(&path)?;
+    // Each dependency recipe has to outlive the BuildCxt parsed from it,
+    // which build_order hands back. That is one string per dependency that
+    // needs building, and this process exits soon enough anyway.
+    let contents: &'static str =
+        Box::leak(read_path_to_string(&path)?.into_boxed_str());
+    match get_config_format(FileType::Unknown, &path) {
+        #[cfg(feature = "serde_json")]
+        FileType::JSON => Ok(serde_json::from_str(contents)?),
+        #[cfg(feature = "toml")]
+        FileType::TOML => Ok(toml::from_str(contents)?),
+        _ => Err("unable to recognize config encoding".into())
+    }
+}
+
+fn build_deps(
+    build_context: &BuildCxt,
+    pkg_dir: &OsString,
+    cache_dir: &Path,
+    mirrors: Option<&Path>,
+    dep_args: &[OsString],
+    verbosity: u8
+) {
+    let order = build_order(
+        build_context,
+        Path::new(pkg_dir),
+        load_dep_recipe
+    ).unwrap_or_else(|e| {
+        eprintln!("Error resolving dependencies of {}:", build_context.pkg_info.pkg_name);
+        print_err_list(&e, 1);
+        std::process::exit(1);
+    });
+    let this_exe = std::env::current_exe().unwrap_or_else(|e| {
+        eprintln!("Unable to find yafpm-build executable: {}", e);
+        std::process::exit(1);
+    });
+    for (url, dep_cxt) in order {
+        // Unwrap is fine, load_dep_recipe only accepts file URLs
+        let recipe_path = url.to_file_path().unwrap();
+        if verbosity > 0 {
+            eprintln!("Building dependency {}", dep_cxt.pkg_info.pkg_ident());
+        }
+        let mut child = Command::new(&this_exe);
+        child.arg("--no-deps").arg("-P").arg(pkg_dir).arg("-C").arg(cache_dir);
+        if let Some(mirrors) = mirrors {
+            child.arg("-M").arg(mirrors);
+        }
+        child.args(dep_args);
+        for _ in 0..verbosity {
+            child.arg("-v");
+        }
+        let status = child.arg(&recipe_path).status().unwrap_or_else(|e| {
+            eprintln!("Unable to run yafpm-build: {}", e);
+            std::process::exit(1);
+        });
+        if !status.success() {
+            eprintln!("Failed to build dependency {} of {}",
+                      dep_cxt.pkg_info.pkg_name,
+                      build_context.pkg_info.pkg_name);
             std::process::exit(1);
         }
-        Err(e) => {
-            eprintln!("Command line parsing error: {}", e);
-            eprintln!("{}", USAGE);
+    }
+}
+
+// The default mirrors file is optional, but one given on the command line
+// isn't.
+fn mirrors_file(mirrors: Option<OsString>, pkg_dir: &Path) -> Option<PathBuf> {
+    match mirrors {
+        Some(m) => Some(PathBuf::from(m)),
+        None => Some(pkg_dir.join(MIRRORS_FILE)).filter(|m| m.exists()),
+    }
+}
+
+fn make_fetch_opts(
+    cache_dir: PathBuf,
+    mirrors: Option<&Path>,
+    verbosity: u8
+) -> FetchOpts {
+    let mut opts = FetchOpts::new();
+    opts.set_cache_dir(cache_dir);
+    if let Some(mirrors) = mirrors {
+        if let Err(e) = opts.load_mirrors(mirrors) {
+            eprintln!("Error loading mirrors:");
+            print_err_list(&e, 1);
             std::process::exit(1);
         }
-    };
+    }
+    if verbosity > 0 {
+        opts.set_progress(|name, received, total| match total {
+            Some(total) => {
+                eprint!("\rDownloading {}: {}/{} KiB", name, received / 1024, total / 1024);
+                if received == total {
+                    eprintln!();
+                }
+            }
+            None => eprint!("\rDownloading {}: {} KiB", name, received / 1024),
+        });
+    }
+    opts
+}
+
+// Prints the hash in a form that can be pasted straight into the recipe
+fn print_hash(pkg: &Package, ft: &FileType) {
+    let version = pkg.hash_version() as u8;
+    // Unwrap is fine, these are the package's outputs
+    let outputs: Vec<_> = pkg.output_names()
+        .filter(|o| *o != MAIN_OUTPUT)
+        .map(|o| (o, pkg.output_hash(o).unwrap()))
+        .collect();
+    match ft {
+        FileType::JSON => {
+            println!("\"hash\": \"{}\",", pkg.hash());
+            if version != 0 {
+                println!("\"hash_version\": {},", version);
+            }
+            if !outputs.is_empty() {
+                let outputs: Vec<_> = outputs.iter()
+                    .map(|(o, h)| format!("{{\"name\": \"{}\", \"hash\": \"{}\"}}", o, h))
+                    .collect();
+                println!("\"outputs\": [{}],", outputs.join(", "));
+            }
+        }
+        _ => {
+            println!("hash = \"{}\"", pkg.hash());
+            if version != 0 {
+                println!("hash_version = {}", version);
+            }
+            if !outputs.is_empty() {
+                let outputs: Vec<_> = outputs.iter()
+                    .map(|(o, h)| format!("{{ name = \"{}\", hash = \"{}\" }}", o, h))
+                    .collect();
+                println!("outputs = [{}]", outputs.join(", "));
+            }
+        }
+    }
+}
+
+// Prints what --check found, returning whether the package is reproducible
+fn print_check(report: &CheckReport, verbosity: u8) -> bool {
+    if verbosity > 0 {
+        eprintln!("Rebuilt {}: {}", report.pkg_info.pkg_ident(), report.usage);
+    }
+    for output in &report.outputs {
+        if output.is_reproducible() {
+            println!("{} is reproducible", output.ident);
+            continue;
+        }
+        println!("{} differs when rebuilt", output.ident);
+        println!("  installed hash: {}", output.installed_hash);
+        println!("  rebuilt hash:   {}", output.rebuilt_hash);
+        for diff in &output.diffs {
+            println!("  {}", diff);
+        }
+    }
+    report.is_reproducible()
+}
+
+fn print_err_list(err: &dyn Error, mut depth: u8) {
+    eprintln!("{:>5}. {}", depth, err);
+    depth += 1;
+    if let Some(err_src) = err.source() {
+        print_err_list(err_src, depth);
+    }
+}
+
+fn main() {
+    let Args{
+        ft, file_str, pkg_dir, cache_dir, mirrors, verbosity, no_deps, discover,
+        check, limits, cgroup, keep_failed, allow_undeclared_refs, dep_args
+    } = parse_args().unwrap_or_else(|e| {
+        eprintln!("Command line parsing error: {}", e);
+        eprintln!("{}", USAGE);
+        std::process::exit(1);
+    });
+    let file_str = file_str.unwrap_or_else(|| {
+        eprintln!("Missing command line argument: <file>");
+        eprintln!("{}", USAGE);
+        std::process::exit(1);
+    });
     let file_path = Path::new(&file_str);
-    if let Err(e) = set_serde_base_url(&file_path) {
+    let recipe_url = set_serde_base_url(file_path).unwrap_or_else(|e| {
         eprintln!("Unable to determine canonical directory of {}", file_path.display());
         eprintln!("Encountered error: {}", e);
         std::process::exit(1);
-    }
-    let contents = match read_path_to_string(&file_path) {
+    });
+    let contents = match read_path_to_string(file_path) {
         Ok(c) => c,
         Err(e) => {
             eprintln!("Error reading {}: {}", file_path.display(), e);
             std::process::exit(1);
         }
     };
-    let build_context: BuildCxt = match get_config_format(ft, file_path) {
+    let ft = get_config_format(ft, file_path);
+    let mut build_context: BuildCxt = match ft {
         #[cfg(feature = "serde_json")]
         FileType::JSON => serde_json::from_str(&contents).unwrap_or_else(|e| {
             eprintln!("Error parsing JSON: {}", e);
@@ -167,29 +451,74 @@ fn main() {
         }
     };
 
+    build_context.set_recipe(recipe_url);
     let pkg_name = build_context.pkg_info.pkg_name;
     let pkg_dir = pkg_dir.unwrap_or(OsString::from(PACKAGE_DIR));
+    let cache_dir = cache_dir.map(PathBuf::from)
+                        .unwrap_or_else(|| Path::new(&pkg_dir).join(CACHE_DIR));
+    let mirrors = mirrors_file(mirrors, Path::new(&pkg_dir));
+    if !no_deps {
+        build_deps(&build_context, &pkg_dir, &cache_dir, mirrors.as_deref(),
+                   &dep_args, verbosity);
+    }
+    build_context.limits_mut().merge(&limits);
+    if let Some(cgroup) = cgroup {
+        build_context.set_cgroup_dir(PathBuf::from(cgroup));
+    }
+    build_context.set_keep_failed(keep_failed);
+    build_context.set_allow_undeclared_refs(allow_undeclared_refs);
+    build_context.set_fetch_opts(
+        make_fetch_opts(cache_dir, mirrors.as_deref(), verbosity));
 
-    if let Err(top_err) = build_context.exec_build(pkg_dir) {
-        eprintln!("Error building {}:", pkg_name);
-        let mut depth = 1;
-        eprintln!("{:>5}. {}", depth, top_err);
-        let mut source_err_opt = top_err.source();
-        while let Some(err) = source_err_opt {
-            depth += 1;
-            // TODO For custom printing certain errors
-            match err {
-                e => eprintln!("{:>5}. {}", depth, e)
-            };
-            source_err_opt = err.source();
-        }
-        if let BuildError::HashError{err: _, teardown_err: Some(e2)} = top_err {
-            eprintln!("");
-            eprintln!("Furthermore, could not remove corrupted directory due to error:",
-            );
-            eprintln!("{:>5}. {}", 1, e2);
+    let res = if check {
+        build_context.check_build(pkg_dir)
+            .map(|report| print_check(&report, verbosity))
+    } else {
+        match discover {
+            Discover::No => build_context.exec_build(pkg_dir),
+            Discover::PrintHash => build_context.discover_hash(pkg_dir, false),
+            Discover::Install => build_context.discover_hash(pkg_dir, true),
+        }.map(|report| {
+            if let (Some(usage), true) = (&report.usage, verbosity > 0) {
+                eprintln!("Built {}: {}", report.pkg_info.pkg_ident(), usage);
+            }
+            for (output, r) in &report.undeclared_refs {
+                eprintln!("Warning: output {} of {} refers to {}, which is not a dependency",
+                          output, pkg_name, r);
+            }
+            if discover != Discover::No {
+                print_hash(&report.pkg_info, &ft);
+            }
+            true
+        })
+    };
+    match res {
+        Ok(true) => (),
+        Ok(false) => std::process::exit(1),
+        Err(top_err) => {
+            eprintln!("Error building {}:", pkg_name);
+            let mut depth = 1;
+            eprintln!("{:>5}. {}", depth, top_err);
+            let mut source_err_opt = top_err.source();
+            while let Some(err) = source_err_opt {
+                depth += 1;
+                // TODO For custom printing certain errors
+                eprintln!("{:>5}. {}", depth, err);
+                source_err_opt = err.source();
+            }
+            if let BuildError::FailedTeardown{teardown_err, ..} = &top_err {
+                eprintln!();
+                eprintln!("Furthermore, could not tear down build environment due to error:");
+                print_err_list(teardown_err, 1);
+            }
+            if let BuildError::HashError{err: _, teardown_err: Some(e2)} = top_err {
+                eprintln!();
+                eprintln!("Furthermore, could not remove corrupted directory due to error:",
+                );
+                eprintln!("{:>5}. {}", 1, e2);
+            }
+            std::process::exit(1);
         }
-        std::process::exit(1);
     }
     std::process::exit(0);
 
@@ -0,0 +1,230 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+// 
+// Copyright (C) 2021 John Arnold
+//
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use std::io;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::ffi::OsString;
+use std::error::Error;
+use std::os::unix::ffi::OsStrExt;
+use url::Url;
+use yafpm::{build_order, BuildCxt, FetchOpts, CACHE_DIR, MIRRORS_FILE};
+
+const USAGE: &str =
+"Usage: yafpm-fetch [-hv] [-P|--package-dir=<pkg_dir>] [-C|--cache-dir=<cache_dir>]
+       [-M|--mirrors=<file>] [--no-deps] <file>...";
+const PACKAGE_DIR: &str = "/yafpm";
+
+struct Args {
+    files: Vec<OsString>,
+    pkg_dir: Option<OsString>,
+    cache_dir: Option<OsString>,
+    mirrors: Option<OsString>,
+    verbosity: u8,
+    no_deps: bool,
+}
+
+fn parse_args() -> Result<Args, lexopt::Error> {
+    use lexopt::prelude::*;
+    let mut args = Args {
+        files: Vec::new(),
+        pkg_dir: None,
+        cache_dir: None,
+        mirrors: None,
+        verbosity: 0,
+        no_deps: false,
+    };
+
+    let mut parser = lexopt::Parser::from_env();
+    while let Some(arg) = parser.next()? {
+        match arg {
+            Long("no-deps") => { args.no_deps = true; }
+            Short('v') => { args.verbosity += 1;}
+            Short('P') | Long("package-dir") => {
+                args.pkg_dir = Some(parser.value()?);
+            }
+            Short('C') | Long("cache-dir") => {
+                args.cache_dir = Some(parser.value()?);
+            }
+            Short('M') | Long("mirrors") => {
+                args.mirrors = Some(parser.value()?);
+            }
+            Short('h') | Long("help") => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            Value(val) => {
+                args.files.push(val);
+            }
+            _ => return Err(arg.unexpected()),
+        }
+    }
+    Ok(args)
+}
+
+fn read_path_to_string<P: AsRef<Path>>(file_name: P) -> Result<String, io::Error> {
+    let fd = File::open(&file_name)?;
+    let mut buf_reader = BufReader::new(fd);
+    let mut contents = String::new();
+    buf_reader.read_to_string(&mut contents)?;
+    Ok(contents)
+}
+
+// So our config file can have things like "url = './build.sh'"
+fn set_serde_base_url(file_path: &Path) -> Result<Url, io::Error> {
+    use yafpm::SERDE_BASE_URL;
+
+    let absolute_path = match file_path.is_absolute() {
+        true => file_path.canonicalize()?,
+        false => {
+            let mut pwd = std::env::current_dir()?;
+            pwd.push(file_path);
+            pwd.canonicalize()?
+        }
+    };
+
+    // Unwraping this should be fine because we already
+    // canonicalize absolute_path
+    let url = Url::from_file_path(absolute_path).unwrap();
+
+    unsafe {
+        SERDE_BASE_URL = Some(url.clone());
+    }
+
+    Ok(url)
+}
+
+fn load_recipe(path: &Path) -> Result<BuildCxt<'static>, Box<dyn Error>> {
+    let url = set_serde_base_url(path)?;
+    // Recipes have to outlive the BuildCxts of the packages that need them,
+    // and this process exits soon enough anyway.
+    let contents: &'static str =
+        Box::leak(read_path_to_string(path)?.into_boxed_str());
+    let mut cxt: BuildCxt = match path.extension().map(|s| s.as_bytes()) {
+        #[cfg(feature = "serde_json")]
+        Some(b"json") => serde_json::from_str(contents)?,
+        #[cfg(feature = "toml")]
+        Some(b"toml") => toml::from_str(contents)?,
+        _ => return Err("unable to recognize config encoding".into())
+    };
+    cxt.set_recipe(url);
+    Ok(cxt)
+}
+
+fn load_dep_recipe(url: &Url) -> Result<BuildCxt<'static>, Box<dyn Error>> {
+    let path = url.to_file_path().map_err(
+        |_| "only local recipes can be used for dependencies")?;
+    load_recipe(&path)
+}
+
+fn print_err_list(err: &dyn Error, mut depth: u8) {
+    eprintln!("{:>5}. {}", depth, err);
+    depth += 1;
+    if let Some(err_src) = err.source() {
+        print_err_list(err_src, depth);
+    }
+}
+
+// The default mirrors file is optional, but one given on the command line
+// isn't.
+fn mirrors_file(mirrors: Option<OsString>, pkg_dir: &Path) -> Option<PathBuf> {
+    match mirrors {
+        Some(m) => Some(PathBuf::from(m)),
+        None => Some(pkg_dir.join(MIRRORS_FILE)).filter(|m| m.exists()),
+    }
+}
+
+fn make_fetch_opts(
+    cache_dir: PathBuf,
+    mirrors: Option<&Path>,
+    verbosity: u8
+) -> FetchOpts {
+    let mut opts = FetchOpts::new();
+    opts.set_cache_dir(cache_dir);
+    if let Some(mirrors) = mirrors {
+        if let Err(e) = opts.load_mirrors(mirrors) {
+            eprintln!("Error loading mirrors:");
+            print_err_list(&e, 1);
+            std::process::exit(1);
+        }
+    }
+    if verbosity > 0 {
+        opts.set_progress(|name, received, total| match total {
+            Some(total) => {
+                eprint!("\rDownloading {}: {}/{} KiB", name, received / 1024, total / 1024);
+                if received == total {
+                    eprintln!();
+                }
+            }
+            None => eprint!("\rDownloading {}: {} KiB", name, received / 1024),
+        });
+    }
+    opts
+}
+
+fn fetch(cxt: &mut BuildCxt, cache_dir: &Path, opts: &FetchOpts, verbosity: u8) {
+    if verbosity > 0 {
+        eprintln!("Fetching resources of {}", cxt.pkg_info.pkg_ident());
+    }
+    cxt.set_fetch_opts(opts.clone());
+    if let Err(e) = cxt.prefetch_resources(cache_dir) {
+        eprintln!("Error fetching resources of {}:", cxt.pkg_info.pkg_name);
+        print_err_list(&e, 1);
+        std::process::exit(1);
+    }
+}
+
+fn main() {
+    let args = parse_args().unwrap_or_else(|e| {
+        eprintln!("Command line parsing error: {}", e);
+        eprintln!("{}", USAGE);
+        std::process::exit(1);
+    });
+    if args.files.is_empty() {
+        eprintln!("Missing command line argument: <file>");
+        eprintln!("{}", USAGE);
+        std::process::exit(1);
+    }
+    let pkg_dir = PathBuf::from(args.pkg_dir.unwrap_or(OsString::from(PACKAGE_DIR)));
+    let cache_dir = args.cache_dir.map(PathBuf::from)
+                        .unwrap_or_else(|| pkg_dir.join(CACHE_DIR));
+    let mirrors = mirrors_file(args.mirrors, &pkg_dir);
+    let opts = make_fetch_opts(cache_dir.clone(), mirrors.as_deref(), args.verbosity);
+
+    for file_str in &args.files {
+        let file_path = Path::new(file_str);
+        let mut cxt = load_recipe(file_path).unwrap_or_else(|e| {
+            eprintln!("Error loading {}: {}", file_path.display(), e);
+            std::process::exit(1);
+        });
+        if !args.no_deps {
+            let order = build_order(&cxt, &pkg_dir, load_dep_recipe)
+                .unwrap_or_else(|e| {
+                    eprintln!("Error resolving dependencies of {}:",
+                              cxt.pkg_info.pkg_name);
+                    print_err_list(&e, 1);
+                    std::process::exit(1);
+                });
+            for (_, mut dep_cxt) in order {
+                fetch(&mut dep_cxt, &cache_dir, &opts, args.verbosity);
+            }
+        }
+        fetch(&mut cxt, &cache_dir, &opts, args.verbosity);
+    }
+    std::process::exit(0);
+}
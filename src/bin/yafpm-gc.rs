@@ -0,0 +1,108 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+// 
+// Copyright (C) 2021 John Arnold
+//
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use std::path::PathBuf;
+use std::ffi::OsString;
+use std::error::Error;
+use yafpm::{collect_garbage, GC_ROOTS_DIR};
+
+const USAGE: &str =
+"Usage: yafpm-gc [-hnv] [-P|--package-dir=<pkg_dir>] [-R|--roots-dir=<roots_dir>]";
+const PACKAGE_DIR: &str = "/yafpm";
+
+struct Args {
+    pkg_dir: Option<OsString>,
+    roots_dir: Option<OsString>,
+    dry_run: bool,
+    verbosity: u8,
+}
+
+fn parse_args() -> Result<Args, lexopt::Error> {
+    use lexopt::prelude::*;
+    let mut args = Args {
+        pkg_dir: None,
+        roots_dir: None,
+        dry_run: false,
+        verbosity: 0,
+    };
+
+    let mut parser = lexopt::Parser::from_env();
+    while let Some(arg) = parser.next()? {
+        match arg {
+            Short('n') | Long("dry-run") => { args.dry_run = true; }
+            Short('v') => { args.verbosity += 1;}
+            Short('P') | Long("package-dir") => {
+                args.pkg_dir = Some(parser.value()?);
+            }
+            Short('R') | Long("roots-dir") => {
+                args.roots_dir = Some(parser.value()?);
+            }
+            Short('h') | Long("help") => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            _ => return Err(arg.unexpected()),
+        }
+    }
+    Ok(args)
+}
+
+fn print_err_list(err: &dyn Error, mut depth: u8) {
+    eprintln!("{:>5}. {}", depth, err);
+    depth += 1;
+    if let Some(err_src) = err.source() {
+        print_err_list(err_src, depth);
+    }
+}
+
+fn main() {
+    let args = parse_args().unwrap_or_else(|e| {
+        eprintln!("Command line parsing error: {}", e);
+        eprintln!("{}", USAGE);
+        std::process::exit(1);
+    });
+    let pkg_dir = PathBuf::from(args.pkg_dir.unwrap_or(OsString::from(PACKAGE_DIR)));
+    // Roots are absolute symlinks as often as not, which only match an
+    // absolute store path
+    let pkg_dir = pkg_dir.canonicalize().unwrap_or_else(|e| {
+        eprintln!("Unable to determine canonical path of {}: {}", pkg_dir.display(), e);
+        std::process::exit(1);
+    });
+    let roots_dir = args.roots_dir.map(PathBuf::from)
+                        .unwrap_or_else(|| pkg_dir.join(GC_ROOTS_DIR));
+
+    match collect_garbage(&pkg_dir, &roots_dir, args.dry_run) {
+        Ok(deleted) => {
+            if args.dry_run || args.verbosity > 0 {
+                for ident in &deleted {
+                    println!("{}", pkg_dir.join(ident).display());
+                }
+            }
+            if args.verbosity > 0 {
+                eprintln!("{} {} packages",
+                          if args.dry_run { "Would delete" } else { "Deleted" },
+                          deleted.len());
+            }
+        }
+        Err(top_err) => {
+            eprintln!("Error while collecting garbage:");
+            print_err_list(&top_err, 1);
+            std::process::exit(1);
+        }
+    }
+    std::process::exit(0);
+}
@@ -0,0 +1,159 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+// 
+// Copyright (C) 2021 John Arnold
+//
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use std::path::{Path, PathBuf};
+use std::ffi::OsString;
+use std::error::Error;
+use yafpm::{read_record, read_all_records, reverse_deps, PkgRecord, StoreError};
+
+const USAGE: &str =
+"Usage: yafpm-query [-h] [-P|--package-dir=<pkg_dir>] [--deps|--rdeps|--info] <pkg>
+       yafpm-query [-h] [-P|--package-dir=<pkg_dir>] --all";
+const PACKAGE_DIR: &str = "/yafpm";
+
+enum Query {
+    Info,
+    Deps,
+    ReverseDeps,
+    All,
+}
+
+struct Args {
+    query: Query,
+    pkg_dir: Option<OsString>,
+    pkg: Option<String>,
+}
+
+fn parse_args() -> Result<Args, lexopt::Error> {
+    use lexopt::prelude::*;
+    let mut args = Args {
+        query: Query::Info,
+        pkg_dir: None,
+        pkg: None,
+    };
+
+    let mut parser = lexopt::Parser::from_env();
+    while let Some(arg) = parser.next()? {
+        match arg {
+            Long("info") => { args.query = Query::Info; }
+            Long("deps") => { args.query = Query::Deps; }
+            Long("rdeps") => { args.query = Query::ReverseDeps; }
+            Short('a') | Long("all") => { args.query = Query::All; }
+            Short('P') | Long("package-dir") => {
+                args.pkg_dir = Some(parser.value()?);
+            }
+            Short('h') | Long("help") => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            Value(val) => {
+                args.pkg = Some(val.into_string()?);
+            }
+            _ => return Err(arg.unexpected()),
+        }
+    }
+    Ok(args)
+}
+
+fn print_err_list(err: &dyn Error, mut depth: u8) {
+    eprintln!("{:>5}. {}", depth, err);
+    depth += 1;
+    if let Some(err_src) = err.source() {
+        print_err_list(err_src, depth);
+    }
+}
+
+fn print_record(record: &PkgRecord) {
+    println!("ident:      {}", record.ident);
+    println!("name:       {}", record.name);
+    println!("version:    {}", record.version);
+    println!("hash:       {} (version {})", record.hash, record.hash_version);
+    println!("registered: {}", record.registered);
+    if let Some(recipe) = &record.recipe {
+        println!("recipe:     {}", recipe);
+    }
+    for dep in &record.deps {
+        println!("dependency: {}", dep);
+    }
+    for r in &record.refs {
+        println!("reference:  {}", r);
+    }
+    for (k, v) in &record.build_settings {
+        println!("setting:    {}={}", k, v);
+    }
+}
+
+fn lookup(pkg_dir: &Path, pkg: &str) -> Result<PkgRecord, StoreError> {
+    // Be forgiving of people pasting in the full store path
+    let ident = Path::new(pkg).strip_prefix(pkg_dir).ok()
+        .and_then(|p| p.to_str())
+        .unwrap_or(pkg);
+    match read_record(pkg_dir, ident)? {
+        Some(record) => Ok(record),
+        None => {
+            eprintln!("{} is not in the store database", ident);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn run(args: Args) -> Result<(), StoreError> {
+    let pkg_dir = PathBuf::from(args.pkg_dir.unwrap_or(OsString::from(PACKAGE_DIR)));
+    let pkg = match (&args.query, args.pkg) {
+        (Query::All, _) => String::new(),
+        (_, Some(pkg)) => pkg,
+        (_, None) => {
+            eprintln!("Missing command line argument: <pkg>");
+            eprintln!("{}", USAGE);
+            std::process::exit(1);
+        }
+    };
+    match args.query {
+        Query::Info => print_record(&lookup(&pkg_dir, &pkg)?),
+        Query::Deps => {
+            for dep in lookup(&pkg_dir, &pkg)?.deps {
+                println!("{}", dep);
+            }
+        }
+        Query::ReverseDeps => {
+            let ident = lookup(&pkg_dir, &pkg)?.ident;
+            for rdep in reverse_deps(&pkg_dir, &ident)? {
+                println!("{}", rdep);
+            }
+        }
+        Query::All => {
+            for record in read_all_records(&pkg_dir)? {
+                println!("{}", record.ident);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let args = parse_args().unwrap_or_else(|e| {
+        eprintln!("Command line parsing error: {}", e);
+        eprintln!("{}", USAGE);
+        std::process::exit(1);
+    });
+    if let Err(top_err) = run(args) {
+        eprintln!("Error while reading the store database:");
+        print_err_list(&top_err, 1);
+        std::process::exit(1);
+    }
+    std::process::exit(0);
+}
@@ -18,59 +18,70 @@
 use std::io;
 use std::fs::File;
 use std::io::{BufReader, Read};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::ffi::OsString;
 use std::error::Error;
 use std::os::unix::ffi::OsStrExt;
-use yafpm::ShellCxt; 
-
-#[cfg(feature = "serde_json")]
-use serde_json;
-#[cfg(feature = "toml")]
-use toml;
+use yafpm::{FetchOpts, ShellCxt, ShellError, CACHE_DIR, MIRRORS_FILE};
 
 const USAGE: &str =
-"Usage: yafpm-shell [-hv] [-P|--package-dir=<pkg_dir>] [--toml|--json] <file>";
+"Usage: yafpm-shell [-hv] [-P|--package-dir=<pkg_dir>] [-C|--cache-dir=<cache_dir>]
+       [-M|--mirrors=<file>] [--toml|--json] <file>";
 const PACKAGE_DIR: &str = "/yafpm";
 
+#[allow(clippy::upper_case_acronyms)]
 enum FileType {
     JSON,
     TOML,
     Unknown
 }
 
-fn parse_args() -> Result<(
-    FileType,
-    Option<OsString>,
-    Option<OsString>,
-    u8
-), lexopt::Error> {
+struct Args {
+    ft: FileType,
+    file_str: Option<OsString>,
+    pkg_dir: Option<OsString>,
+    cache_dir: Option<OsString>,
+    mirrors: Option<OsString>,
+    verbosity: u8,
+}
+
+fn parse_args() -> Result<Args, lexopt::Error> {
     use lexopt::prelude::*;
-    let mut ft = FileType::Unknown;
-    let mut file_str = None;
-    let mut pkg_dir = None;
-    let mut verbosity: u8 = 0;
+    let mut args = Args {
+        ft: FileType::Unknown,
+        file_str: None,
+        pkg_dir: None,
+        cache_dir: None,
+        mirrors: None,
+        verbosity: 0,
+    };
 
     let mut parser = lexopt::Parser::from_env();
     while let Some(arg) = parser.next()? {
         match arg {
-            Long("json") => { ft = FileType::JSON; }
-            Long("toml") => { ft = FileType::TOML; }
-            Short('v') => { verbosity += 1;}
+            Long("json") => { args.ft = FileType::JSON; }
+            Long("toml") => { args.ft = FileType::TOML; }
+            Short('v') => { args.verbosity += 1;}
             Short('P') | Long("package-dir") => {
-                pkg_dir = Some(parser.value()?);
+                args.pkg_dir = Some(parser.value()?);
+            }
+            Short('C') | Long("cache-dir") => {
+                args.cache_dir = Some(parser.value()?);
+            }
+            Short('M') | Long("mirrors") => {
+                args.mirrors = Some(parser.value()?);
             }
             Short('h') | Long("help") => {
                 println!("{}", USAGE);
                 std::process::exit(0);
             }
             Value(val) => {
-                file_str = Some(val);
+                args.file_str = Some(val);
             }
             _ => return Err(arg.unexpected()),
         }
     }
-    Ok((ft, file_str, pkg_dir, verbosity))
+    Ok(args)
 }
 
 fn read_path_to_string<P: AsRef<Path>>(file_name: P) -> Result<String, io::Error> {
@@ -130,10 +141,10 @@ fn print_err_list(err: &dyn Error, mut depth: u8) {
 }
 
 fn main() {
-    let (ft, file_str, pkg_dir, _verbosity) = match parse_args() {
-        Ok((ft, Some(file_str), pkg_dir, verbosity)) =>
-            (ft, file_str, pkg_dir, verbosity),
-        Ok((_, None, _, _)) => {
+    let (ft, file_str, pkg_dir, cache_dir, mirrors) = match parse_args() {
+        Ok(Args{ft, file_str: Some(file_str), pkg_dir, cache_dir, mirrors, ..}) =>
+            (ft, file_str, pkg_dir, cache_dir, mirrors),
+        Ok(Args{file_str: None, ..}) => {
             eprintln!("Missing command line argument: <file>");
             eprintln!("{}", USAGE);
             std::process::exit(1);
@@ -145,16 +156,16 @@ fn main() {
         }
     };
     let file_path = Path::new(&file_str);
-    let contents = read_path_to_string(&file_path).unwrap_or_else(|e| {
+    let contents = read_path_to_string(file_path).unwrap_or_else(|e| {
         eprintln!("Error reading {}: {}", file_path.display(), e);
         std::process::exit(1);
     });
-    if let Err(e) = set_serde_base_url(&file_path) {
+    if let Err(e) = set_serde_base_url(file_path) {
         eprintln!("Unable to determine canonical directory of {}", file_path.display());
         eprintln!("Encountered error: {}", e);
         std::process::exit(1);
     }
-    let shell_context: ShellCxt = match get_config_format(ft, file_path) {
+    let mut shell_context: ShellCxt = match get_config_format(ft, file_path) {
         #[cfg(feature = "serde_json")]
         FileType::JSON => serde_json::from_str(&contents).unwrap_or_else(|e| {
             eprintln!("Error parsing JSON: {}", e);
@@ -173,11 +184,34 @@ fn main() {
     };
 
     let pkg_dir = pkg_dir.unwrap_or(OsString::from(PACKAGE_DIR));
+    let cache_dir = cache_dir.map(PathBuf::from)
+                        .unwrap_or_else(|| Path::new(&pkg_dir).join(CACHE_DIR));
+    let mut fetch_opts = FetchOpts::new();
+    fetch_opts.set_cache_dir(cache_dir);
+    // The default mirrors file is optional, but one given on the command line
+    // isn't.
+    let mirrors = match mirrors {
+        Some(m) => Some(PathBuf::from(m)),
+        None => Some(Path::new(&pkg_dir).join(MIRRORS_FILE)).filter(|m| m.exists()),
+    };
+    if let Some(mirrors) = mirrors {
+        if let Err(e) = fetch_opts.load_mirrors(&mirrors) {
+            eprintln!("Error loading mirrors:");
+            print_err_list(&e, 1);
+            std::process::exit(1);
+        }
+    }
+    shell_context.set_fetch_opts(fetch_opts);
 
     if let Err(top_err) = shell_context.enter_shell(pkg_dir) {
         eprintln!("Error while creating shell environment:");
         let depth = 1;
         print_err_list(&top_err, depth);
+        if let ShellError::FailedTeardown{teardown_err, ..} = &top_err {
+            eprintln!();
+            eprintln!("Furthermore, could not tear down shell environment due to error:");
+            print_err_list(teardown_err, depth);
+        }
         std::process::exit(1);
     }
     std::process::exit(0);
@@ -0,0 +1,157 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+// 
+// Copyright (C) 2021 John Arnold
+//
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! Running a build in a cgroup v2 of its own.
+//!
+//! The cgroup is made as a child of a cgroup that has been delegated to the
+//! user running yafpm, for instance by a systemd unit with `Delegate=yes`. As
+//! cgroups with controllers enabled for their children can't hold processes
+//! themselves, the delegated cgroup must not be the one yafpm runs in.
+
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+
+use crate::limits::BuildLimits;
+
+#[derive(Debug, thiserror::Error)]
+pub enum CgroupError {
+    #[error("IO error while accessing {}", .file.display())]
+    IOError {
+        #[source]
+        err: io::Error,
+        file: PathBuf
+    },
+    #[error("The {controller} controller is not available in {}", .dir.display())]
+    MissingController {
+        controller: &'static str,
+        dir: PathBuf,
+    },
+}
+
+fn io_err(file: &Path) -> impl FnOnce(io::Error) -> CgroupError + '_ {
+    move |err| CgroupError::IOError{err, file: file.to_path_buf()}
+}
+
+fn write_file(file: &Path, contents: &str) -> Result<(), CgroupError> {
+    fs::write(file, contents).map_err(io_err(file))
+}
+
+/// The cgroup of a single build, which is removed when dropped.
+pub(crate) struct Cgroup {
+    dir: PathBuf,
+    procs: File,
+}
+
+impl Cgroup {
+    /// Makes a cgroup called `name` in the delegated cgroup `parent`, with
+    /// the memory, CPU and process limits from `limits`. The memory
+    /// controller is always enabled if it can be, to measure peak usage.
+    pub(crate) fn create(
+        parent: &Path,
+        name: &str,
+        limits: &BuildLimits
+    ) -> Result<Cgroup, CgroupError> {
+        let controllers_file = parent.join("cgroup.controllers");
+        let available = fs::read_to_string(&controllers_file)
+            .map_err(io_err(&controllers_file))?;
+        let available: Vec<&str> = available.split_whitespace().collect();
+        let mut wanted = Vec::new();
+        for (controller, needed) in &[
+            ("memory", limits.memory().is_some()),
+            ("pids", limits.processes().is_some()),
+            ("cpu", limits.cpus().is_some()),
+        ] {
+            if available.contains(controller) {
+                wanted.push(*controller);
+            } else if *needed {
+                return Err(CgroupError::MissingController{
+                    controller,
+                    dir: parent.to_path_buf()
+                });
+            }
+        }
+        if !wanted.is_empty() {
+            let enable: Vec<String> = wanted.iter()
+                .map(|c| format!("+{}", c))
+                .collect();
+            write_file(&parent.join("cgroup.subtree_control"), &enable.join(" "))?;
+        }
+
+        let dir = parent.join(name);
+        fs::create_dir(&dir).map_err(io_err(&dir))?;
+        let res = Self::set_limits(&dir, limits).and_then(|_| {
+            let procs_file = dir.join("cgroup.procs");
+            OpenOptions::new().write(true).open(&procs_file)
+                .map_err(io_err(&procs_file))
+        });
+        match res {
+            Ok(procs) => Ok(Cgroup { dir, procs }),
+            Err(e) => {
+                let _ = fs::remove_dir(&dir);
+                Err(e)
+            }
+        }
+    }
+
+    fn set_limits(dir: &Path, limits: &BuildLimits) -> Result<(), CgroupError> {
+        if let Some(memory) = limits.memory() {
+            write_file(&dir.join("memory.max"), &memory.to_string())?;
+            // Otherwise the limit only holds until the build starts swapping,
+            // but not every kernel has swap accounting
+            let _ = fs::write(dir.join("memory.swap.max"), "0");
+        }
+        if let Some(processes) = limits.processes() {
+            write_file(&dir.join("pids.max"), &processes.to_string())?;
+        }
+        if let Some(cpus) = limits.cpus() {
+            let period = 100_000;
+            let quota = u64::from(cpus) * period;
+            write_file(&dir.join("cpu.max"), &format!("{} {}", quota, period))?;
+        }
+        Ok(())
+    }
+
+    /// The `cgroup.procs` file of the cgroup, for [Cgroup::enter].
+    pub(crate) fn procs_fd(&self) -> RawFd {
+        self.procs.as_raw_fd()
+    }
+
+    /// Moves the calling process into the cgroup whose `cgroup.procs` file
+    /// is open as `procs_fd`. This is meant to be called from `pre_exec`, so
+    /// that the whole build ends up in the cgroup.
+    pub(crate) fn enter(procs_fd: RawFd) -> Result<(), nix::Error> {
+        nix::unistd::write(procs_fd, b"0").map(|_| ())
+    }
+
+    /// The most memory the processes in the cgroup have used at once, if the
+    /// kernel keeps track of it.
+    pub(crate) fn peak_memory(&self) -> Option<u64> {
+        fs::read_to_string(self.dir.join("memory.peak")).ok()?
+            .trim().parse().ok()
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        // Only works once every process in it is gone, which it is once the
+        // build's init has been waited for
+        let _ = fs::remove_dir(&self.dir);
+    }
+}
@@ -15,29 +15,41 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::io;
 use std::iter::Chain;
 use std::path::{Path, PathBuf};
-use std::process::{Command,ExitStatus};
+use std::process::{Command,ExitStatus,Stdio};
 use std::slice::Iter;
 use std::os::unix::process::CommandExt;
-use blake2::Blake2s;
-use nix::unistd::chroot;
+use url::Url;
 
 use crate::dirs;
 use crate::hashes;
-use crate::walk_dir;
+use crate::walk_dir::{self, DirHashVersion};
+use crate::refs;
+use crate::dir_diff::{self, FileDiff};
 use crate::namespace;
+use crate::namespace::Sandbox;
+use crate::init;
+use crate::limits::{self, BuildLimits, ResourceUsage, UsageMeter};
+use crate::cgroup::{Cgroup, CgroupError};
+use super::guard::{ContextGuard, TeardownError};
+use crate::store;
 use crate::resource;
 use crate::resource::Resource as RS;
-use crate::package::Package as PKG;
+use crate::resource::FetchOpts;
+use crate::patch::{Patch, PatchError};
+use crate::package::{Package as PKG, MAIN_OUTPUT};
 use super::Context;
+use super::monitor::{self, Outcome};
 
 #[cfg(feature = "serde")]
 use serde::{Serialize, Deserialize};
 
 #[derive(Debug, thiserror::Error)]
+#[allow(clippy::enum_variant_names)]
 pub enum InnerBuildError {
     #[error(transparent)]
     IOError(#[from] io::Error),
@@ -47,8 +59,13 @@ pub enum InnerBuildError {
     RSError(#[from] resource::ResourceError),
     #[error(transparent)]
     CXTError(#[from] super::ContextPrepError),
-    #[error("The output directory already exists")]
-    MaybeAlreadyInstalled(String),
+    #[error("Unable to set up the init of the build")]
+    InitError(#[source] nix::Error),
+    #[error("Unable to set up the cgroup of the build")]
+    CgroupError(#[source] CgroupError),
+    #[error("Invalid output name {0:?}, output names are made of lowercase \
+             letters, digits and underscores, and may not be repeated or be \"out\"")]
+    OutputNameError(String),
 }
 #[derive(Debug, thiserror::Error)]
 /// The error returned by [BuildCxt].
@@ -57,14 +74,42 @@ pub enum BuildError {
     CanonicalizeError{err:io::Error, path: PathBuf},
     #[error("Error while setting up build environment")]
     SetupError(#[source] InnerBuildError),
+    #[error("Unable to apply patch {patch}")]
+    PatchError{#[source] err: PatchError, patch: String},
     #[error("Unable to execute build command")]
     ExecBuildCmdError(#[source] io::Error),
     #[error("Build process error: {0}")]
     BuildCmdError(ExitStatus),
+    #[error("Build killed after {} {secs} seconds",
+            if *.silent { "printing nothing for" } else { "running for" })]
+    Timeout{secs: u64, silent: bool},
+    #[error("Build output takes up {used} bytes, more than the limit of {limit}")]
+    DiskLimitError{used: u64, limit: u64},
+    #[error("Unable to measure the size of the build output")]
+    OutputSizeError(#[source] io::Error),
+    #[error("Build failed, what it left behind is kept in {}", .dir.display())]
+    KeptFailedBuild{#[source] err: Box<BuildError>, dir: PathBuf},
     #[error("Error while hashing build result")]
     HashError{#[source] err: hashes::HashError, teardown_err: Option<io::Error>},
+    #[error("{} is not installed, so there is nothing to check", .0.display())]
+    NotInstalled(PathBuf),
+    #[error("Unable to compare rebuilt output with installed one")]
+    CompareError(#[source] io::Error),
+    #[error("Unable to scan build output for references")]
+    RefScanError(#[source] io::Error),
+    #[error("Build output refers to packages it does not depend on: {}",
+            list_refs(.refs))]
+    UndeclaredRefs{refs: Vec<(String, String)>},
+    #[error("Unable to mark the package as being built in the package store")]
+    LockError(#[source] store::StoreError),
+    #[error("Error while recording build result in the package store")]
+    RegisterError(#[source] store::StoreError),
+    #[error("Unable to move build output to {}", .path.display())]
+    InstallError{#[source] err: io::Error, path: PathBuf},
     #[error("Error while tearing down build environment")]
-    TeardownError(#[source] InnerBuildError)
+    TeardownError(#[source] TeardownError),
+    #[error("Build failed, and then its environment could not be torn down")]
+    FailedTeardown{#[source] err: Box<BuildError>, teardown_err: TeardownError},
 }
 
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
@@ -76,6 +121,9 @@ pub struct BuildCxt<'a> {
     #[cfg_attr(feature = "serde", serde(rename = "resources"))]
     #[cfg_attr(feature = "serde", serde(borrow))]
     srcs: Vec<RS<'a>>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    #[cfg_attr(feature = "serde", serde(borrow))]
+    patches: Vec<Patch<'a>>,
     #[cfg_attr(feature = "serde", serde(rename = "build_dependencies"))]
     #[cfg_attr(feature = "serde", serde(default))]
     #[cfg_attr(feature = "serde", serde(borrow))]
@@ -86,6 +134,89 @@ pub struct BuildCxt<'a> {
     #[cfg_attr(feature = "serde", serde(default))]
     #[cfg_attr(feature = "serde", serde(borrow))]
     build_cmd_args: Vec<&'a str>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    #[cfg_attr(feature = "serde", serde(borrow))]
+    sandbox: Option<Sandbox<'a>>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    limits: BuildLimits,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    cgroup_dir: Option<PathBuf>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    keep_failed: bool,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    allow_undeclared_refs: bool,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    fetch_opts: FetchOpts,
+}
+
+// Where setup_out_dirs makes the output directories
+#[derive(Clone, Copy, PartialEq)]
+enum OutDirMode {
+    // Where each output is installed, or if it already is, in a scratch
+    // directory that the build sees there
+    Install,
+    // In scratch directories, which the build sees as they are
+    Scratch,
+    // In scratch directories that the build sees where the outputs are
+    // installed
+    Rebuild,
+}
+
+// An output directory of a build
+struct OutDir<'a> {
+    name: &'a str,
+    // Where the build writes the output
+    dir: PathBuf,
+    // Where the build sees it, which is where it's installed, unless dir is
+    // a scratch directory whose output is only hashed
+    seen_as: PathBuf,
+}
+
+/// What [BuildCxt::exec_build] and [BuildCxt::discover_hash] return.
+pub struct BuildReport<'a> {
+    pub pkg_info: PKG<'a>,
+    /// What the build used, or `None` if the package was already installed,
+    /// so that nothing was built.
+    pub usage: Option<ResourceUsage>,
+    /// The references from an output, by name, to packages that aren't
+    /// among the dependencies of the package. These fail the build unless
+    /// [BuildCxt::set_allow_undeclared_refs] is set.
+    pub undeclared_refs: Vec<(String, String)>,
+}
+
+/// What [BuildCxt::check_build] found for one output.
+pub struct OutputCheck {
+    pub ident: String,
+    pub installed_hash: hashes::ItemHash,
+    pub rebuilt_hash: hashes::ItemHash,
+    /// How the rebuilt output differs from the installed one, which is only
+    /// looked into when the hashes differ.
+    pub diffs: Vec<FileDiff>,
+}
+
+impl OutputCheck {
+    pub fn is_reproducible(&self) -> bool {
+        self.installed_hash == self.rebuilt_hash
+    }
+}
+
+/// What [BuildCxt::check_build] returns.
+pub struct CheckReport<'a> {
+    pub pkg_info: PKG<'a>,
+    pub usage: ResourceUsage,
+    pub outputs: Vec<OutputCheck>,
+}
+
+impl CheckReport<'_> {
+    pub fn is_reproducible(&self) -> bool {
+        self.outputs.iter().all(OutputCheck::is_reproducible)
+    }
+}
+
+// The references found in the outputs of a build
+struct FoundRefs<'a> {
+    by_output: HashMap<&'a str, Vec<String>>,
+    undeclared: Vec<(String, String)>,
 }
 
 impl<'a> Context<'a> for BuildCxt<'a> {
@@ -106,13 +237,21 @@ impl<'a> Context<'a> for BuildCxt<'a> {
     fn dependencies(&'a self) -> Self::D {
         self.pkg_info.deps.iter().chain(&self.build_deps)
     }
+
+    fn fetch_opts(&self) -> &FetchOpts {
+        &self.fetch_opts
+    }
+
+    fn sandbox(&self) -> Option<&Sandbox<'a>> {
+        self.sandbox.as_ref()
+    }
 }
 
 impl<'a> BuildCxt<'a> {
     pub fn new(
         pkg_name: &'a str,
         pkg_version: &'a str,
-        hash: hashes::ItemHash<Blake2s>,
+        hash: hashes::ItemHash,
         build_cmd: &'a str,
     ) -> Self {
         let pgk_info = PKG::new(
@@ -123,9 +262,16 @@ impl<'a> BuildCxt<'a> {
         BuildCxt {
             pkg_info: pgk_info,
             srcs: Vec::new(),
+            patches: Vec::new(),
             build_deps: Vec::new(),
             build_cmd,
             build_cmd_args: Vec::new(),
+            sandbox: None,
+            limits: BuildLimits::default(),
+            cgroup_dir: None,
+            keep_failed: false,
+            allow_undeclared_refs: false,
+            fetch_opts: FetchOpts::default(),
         }
     }
 
@@ -143,6 +289,13 @@ impl<'a> BuildCxt<'a> {
         self
     }
 
+    pub fn add_patches<I>(&mut self, iter: I) -> &mut Self
+        where I: IntoIterator<Item = Patch<'a>>
+    {
+        self.patches.extend(iter);
+        self
+    }
+
     pub fn add_build_deps<I>(&mut self, iter: I) -> &mut Self
         where I: IntoIterator<Item = PKG<'a>>
     {
@@ -150,6 +303,13 @@ impl<'a> BuildCxt<'a> {
         self
     }
 
+    /// Records where this context was read from, which is kept in the
+    /// package store database once the package is built.
+    pub fn set_recipe(&mut self, recipe: Url) -> &mut Self {
+        self.pkg_info.set_recipe(recipe);
+        self
+    }
+
     pub fn add_build_cmd_args<I>(&mut self, iter: I) -> &mut Self
         where I: IntoIterator<Item = &'a str>
     {
@@ -157,26 +317,145 @@ impl<'a> BuildCxt<'a> {
         self
     }
 
-    fn setup_out_dir(
+    /// Sets up `/proc`, `/dev` and `/tmp` in the build's root directory as
+    /// `sandbox` says. Without this, the root holds nothing but the
+    /// resources and dependencies.
+    pub fn set_sandbox(&mut self, sandbox: Sandbox<'a>) -> &mut Self {
+        self.sandbox = Some(sandbox);
+        self
+    }
+
+    /// The limits on the build, which start out as those of the recipe.
+    pub fn limits_mut(&mut self) -> &mut BuildLimits {
+        &mut self.limits
+    }
+
+    /// Runs the build in a cgroup of its own, made in the delegated cgroup
+    /// `dir`, which also enforces the memory, CPU and process limits.
+    pub fn set_cgroup_dir(&mut self, dir: PathBuf) -> &mut Self {
+        self.cgroup_dir = Some(dir);
+        self
+    }
+
+    /// If the build fails, keeps its root directory, unmounted, with what
+    /// the build had written to its output directories moved into it, rather
+    /// than removing both. The error then says where it is.
+    pub fn set_keep_failed(&mut self, keep: bool) -> &mut Self {
+        self.keep_failed = keep;
+        self
+    }
+
+    /// Lets the outputs refer to packages that aren't among the dependencies
+    /// of the package, such as build dependencies, rather than failing the
+    /// build. They are still recorded in the store database, and listed in
+    /// the [BuildReport].
+    pub fn set_allow_undeclared_refs(&mut self, allow: bool) -> &mut Self {
+        self.allow_undeclared_refs = allow;
+        self
+    }
+
+    pub fn set_fetch_opts(&mut self, opts: FetchOpts) -> &mut Self {
+        self.fetch_opts = opts;
+        self
+    }
+
+    /// Puts every resource of this package in `cache_dir`, so that it can
+    /// later be built without fetching anything. See [RS::prefetch].
+    pub fn prefetch_resources(
+        &self,
+        cache_dir: &Path
+    ) -> Result<(), resource::ResourceError> {
+        for src in &self.srcs {
+            src.prefetch(cache_dir, &self.fetch_opts)?;
+        }
+        for patch in &self.patches {
+            patch.resource().prefetch(cache_dir, &self.fetch_opts)?;
+        }
+        Ok(())
+    }
+
+    fn apply_patches(&self, build_dir: &Path) -> Result<(), BuildError> {
+        for patch in &self.patches {
+            patch.apply(build_dir, &self.fetch_opts).map_err(
+                |e| BuildError::PatchError{
+                    err: e,
+                    patch: patch.name().to_string()
+                })?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn all_deps(&self) -> impl Iterator<Item = &PKG<'a>> {
+        self.pkg_info.deps.iter().chain(&self.build_deps)
+    }
+
+    fn check_output_names(&self) -> Result<(), InnerBuildError> {
+        let mut seen = Vec::new();
+        for output in &self.pkg_info.outputs {
+            let name = output.name;
+            let valid = !name.is_empty()
+                && name != MAIN_OUTPUT
+                && name.bytes().all(|b| b.is_ascii_lowercase()
+                    || b.is_ascii_digit() || b == b'_')
+                && !seen.contains(&name);
+            if !valid {
+                return Err(InnerBuildError::OutputNameError(name.to_string()));
+            }
+            seen.push(name);
+        }
+        Ok(())
+    }
+
+    // Makes an output directory in the store for each output as `mode` says
+    // and mounts it in the root directory of `guard`, which then owns them
+    fn setup_out_dirs(
         &self,
         pkg_store_dir: &Path,
-        build_dir: &Path,
-    ) -> Result<PathBuf, InnerBuildError> {
-        let pkg_ident = self.pkg_info.pkg_ident();
-        let out_dir = dirs::create_outdir(&pkg_store_dir, &pkg_ident).map_err(
-            |e| if let Some(17) = e.raw_os_error() {
-                InnerBuildError::MaybeAlreadyInstalled(pkg_ident)
-            } else { InnerBuildError::IOError(e) })?;
-        namespace::mount_out_dir(build_dir, &out_dir)?;
-        Ok(out_dir)
+        guard: &mut ContextGuard,
+        mode: OutDirMode
+    ) -> Result<Vec<OutDir<'a>>, InnerBuildError> {
+        let build_dir = guard.dir().clone();
+        // Unwrap is fine, the root directory is named after the context
+        let context_name = build_dir.file_name().unwrap().to_string_lossy();
+        let mut out_dirs = Vec::new();
+        for name in self.pkg_info.output_names() {
+            // Not named like a package, so that the garbage collector ignores it
+            let scratch_name = format!("{}-{}", context_name, name);
+            // Unwrap is fine, name is one of the outputs
+            let ident = self.pkg_info.output_ident(name).unwrap();
+            let (dir, seen_as) = match mode {
+                OutDirMode::Scratch => {
+                    let dir = dirs::create_outdir(pkg_store_dir, &scratch_name)?;
+                    (dir.clone(), dir)
+                }
+                OutDirMode::Rebuild => (
+                    dirs::create_outdir(pkg_store_dir, &scratch_name)?,
+                    pkg_store_dir.join(&ident)
+                ),
+                OutDirMode::Install => match dirs::create_outdir(pkg_store_dir, &ident) {
+                    Ok(dir) => (dir.clone(), dir),
+                    // Built again anyway to check it, but where the build
+                    // expects it to be
+                    Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                        let dir = dirs::create_outdir(pkg_store_dir, &scratch_name)?;
+                        (dir, pkg_store_dir.join(&ident))
+                    }
+                    Err(e) => return Err(e.into()),
+                },
+            };
+            guard.add_out_dir(dir.clone(), seen_as.clone());
+            namespace::mount_out_dir(&build_dir, &dir, &seen_as, guard.mounts_mut())?;
+            out_dirs.push(OutDir{name, dir, seen_as});
+        }
+        Ok(out_dirs)
     }
 
     fn exec_build_cmd<P: AsRef<Path>> (
         &self,
         pkg_store_dir: P,
         build_dir: &PathBuf,
-        out_dir: &PathBuf
-    ) -> Result<(), BuildError> {
+        out_dirs: &[OutDir]
+    ) -> Result<ResourceUsage, BuildError> {
         let dep_env_clos = |d: &PKG<'a>|
             (d.pkg_name, pkg_store_dir.as_ref().join(d.pkg_ident()));
         let mut child = Command::new(self.build_cmd);
@@ -185,14 +464,32 @@ impl<'a> BuildCxt<'a> {
              .envs(self.build_deps.iter().map(dep_env_clos))
              .envs(self.pkg_info.deps.iter().map(dep_env_clos))
              .envs(&self.pkg_info.build_settings)
-             .env("out", out_dir.as_os_str())
+             .envs(out_dirs.iter().map(|o| (o.name, &o.seen_as)))
              .env("PATH", self.make_path_string(pkg_store_dir.as_ref()))
-             .current_dir(&build_dir);
-        // TODO there has to be an more elegant way of doing this
-        let build_dir_clone = build_dir.clone();
+             .stdout(Stdio::piped())
+             .stderr(Stdio::piped())
+             .current_dir(build_dir);
+        let context_name = self.context_name();
+        let cgroup = match &self.cgroup_dir {
+            Some(dir) => Some(Cgroup::create(dir, &context_name, &self.limits)
+                .map_err(|e| BuildError::SetupError(
+                    InnerBuildError::CgroupError(e)))?),
+            None => None,
+        };
+        let status_pipe = init::StatusPipe::new().map_err(
+            |e| BuildError::SetupError(InnerBuildError::InitError(e)))?;
+        let proc = self.sandbox.as_ref().is_some_and(Sandbox::proc);
+        let new_root = namespace::NewRoot::new(build_dir, proc)
+            .map_err(BuildError::ExecBuildCmdError)?;
+        let status_fd = status_pipe.writer();
+        let procs_fd = cgroup.as_ref().map(Cgroup::procs_fd);
+        let limits = self.limits;
         unsafe {
             child.pre_exec(move || {
-                let res = chroot(&build_dir_clone);
+                let res = procs_fd.map_or(Ok(()), Cgroup::enter)
+                    .and_then(|_| new_root.enter())
+                    .and_then(|_| init::become_init(status_fd))
+                    .and_then(|_| limits.apply());
                 res.map_err(|e| if let Some(errno) = e.as_errno() {
                     io::Error::from_raw_os_error(errno as i32)
                 } else {
@@ -200,23 +497,85 @@ impl<'a> BuildCxt<'a> {
                 })
             });
         }
-        let exit_status = child.status().map_err(
-            |e| BuildError::ExecBuildCmdError(e)
-        )?;
-        if exit_status.success() {
-            Ok(())
+        let meter = UsageMeter::start();
+        let mut child = child.spawn().map_err(BuildError::ExecBuildCmdError)?;
+        let outcome = monitor::watch(&mut child, &self.limits);
+        if outcome.is_err() {
+            // Leave nothing running in the sandbox we're giving up on
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        let mut usage = meter.finish();
+        usage.peak_memory = cgroup.as_ref().and_then(Cgroup::peak_memory);
+        drop(cgroup);
+        let init_status = match outcome {
+            Ok(Outcome::Exited(status)) => status,
+            Ok(Outcome::TimedOut{secs, silent}) => {
+                return Err(BuildError::Timeout{secs, silent});
+            }
+            Err(e) => return Err(BuildError::ExecBuildCmdError(e)),
+        };
+        // Only init's own status if it died before the build command did
+        let status = status_pipe.read_status().unwrap_or(init_status);
+        if status.success() {
+            Ok(usage)
         } else {
-            Err(BuildError::BuildCmdError(exit_status))
+            Err(BuildError::BuildCmdError(status))
         }
+    }
 
+    fn check_build_hash(
+        &self,
+        output: &str,
+        out_dir: &Path
+    ) -> Result<(), hashes::HashError> {
+        let version = self.pkg_info.hash_version;
+        // Unwrap is fine, output is one of the package's
+        self.pkg_info.output_hash(output).unwrap().verify_hash_from_fn(
+            |dir, h| walk_dir::calculate_directory_hash(dir, version, h),
+            &out_dir)
     }
 
-    fn verify_build_hash(&self, out_dir: &PathBuf) -> Result<(), BuildError> {
-        let res = self.pkg_info.hash.verify_hash_from_fn(
-            walk_dir::calculate_directory_hash,
-            &out_dir);
-        if let Err(e) = res {
-            let e2 = fs::remove_dir_all(&out_dir).err();
+    // Scans each of `outputs`, given by name and directory, for references to
+    // the packages the build could see. The package's own outputs can only
+    // be looked for if `own_outputs` is set, as they aren't known before
+    // their hashes are.
+    fn find_refs<'o, I>(&self, outputs: I, own_outputs: bool) -> Result<FoundRefs<'a>, BuildError>
+        where I: IntoIterator<Item = (&'a str, &'o Path)>
+    {
+        let mut visible: Vec<String> = self.all_deps().map(PKG::pkg_ident).collect();
+        let mut declared: HashSet<String> = self.pkg_info.deps.iter()
+            .map(PKG::pkg_ident)
+            .collect();
+        if own_outputs {
+            // Unwrap is fine, these are the package's outputs
+            for output in self.pkg_info.output_names() {
+                let ident = self.pkg_info.output_ident(output).unwrap();
+                visible.push(ident.clone());
+                declared.insert(ident);
+            }
+        }
+        let mut found = FoundRefs{by_output: HashMap::new(), undeclared: Vec::new()};
+        for (output, dir) in outputs {
+            let own_ident = self.pkg_info.output_ident(output);
+            let mut refs = refs::scan_refs(dir, &visible).map_err(
+                BuildError::RefScanError)?;
+            refs.retain(|r| Some(r) != own_ident.as_ref());
+            found.undeclared.extend(refs.iter()
+                .filter(|r| !declared.contains(*r))
+                .map(|r| (output.to_string(), r.clone())));
+            found.by_output.insert(output, refs);
+        }
+        if !found.undeclared.is_empty() && !self.allow_undeclared_refs {
+            return Err(BuildError::UndeclaredRefs{refs: found.undeclared});
+        }
+        Ok(found)
+    }
+
+    // Checks an output that is already installed, removing it if it's wrong
+    fn verify_build_hash(&self, output: &str, out_dir: &Path) -> Result<(), BuildError> {
+        if let Err(e) = self.check_build_hash(output, out_dir) {
+            let e2 = fs::remove_dir_all(out_dir).err();
             return Err(BuildError::HashError{
                 err: e,
                 teardown_err: e2,
@@ -225,64 +584,354 @@ impl<'a> BuildCxt<'a> {
         Ok(())
     }
 
-    fn cleanup_post_build<P: AsRef<Path>> (
+    // Called when a build fails with `err` once its root directory is set up.
+    // Everything is torn down, so that no half-written output is left in the
+    // store, unless the build is to be kept.
+    fn fail_build(&self, mut guard: ContextGuard, err: BuildError) -> BuildError {
+        if !self.keep_failed {
+            return teardown_after(guard, err);
+        }
+        match guard.keep() {
+            Ok(dir) => BuildError::KeptFailedBuild{err: Box::new(err), dir},
+            // The guard still tears down what it can when dropped
+            Err(teardown_err) => BuildError::FailedTeardown{
+                err: Box::new(err),
+                teardown_err
+            },
+        }
+    }
+
+    // Runs the build command and checks the size of its outputs
+    fn run_build_cmd(
         &self,
-        pkg_store_dir: P,
+        pkg_store_dir: &Path,
         build_dir: &PathBuf,
-        out_dir: &PathBuf
-    ) -> Result<(), InnerBuildError> {
-        dirs::set_readonly_all(&out_dir, true)?;
-        namespace::umount_out_dir(build_dir, out_dir)?;
-        namespace::umount_dep_dirs(&pkg_store_dir.as_ref(),
-                                   &build_dir,
-                                   self.dependencies())?;
-        fs::remove_dir_all(&build_dir)?;
-        Ok(())
+        out_dirs: &[OutDir]
+    ) -> Result<ResourceUsage, BuildError> {
+        self.exec_build_cmd(pkg_store_dir, build_dir, out_dirs)
+            .and_then(|mut usage| {
+                for out_dir in out_dirs {
+                    usage.output_size += limits::disk_usage(&out_dir.dir)
+                        .map_err(BuildError::OutputSizeError)?;
+                }
+                match self.limits.disk() {
+                    Some(limit) if usage.output_size > limit => {
+                        Err(BuildError::DiskLimitError{
+                            used: usage.output_size,
+                            limit
+                        })
+                    }
+                    _ => Ok(usage),
+                }
+            })
     }
 
     pub fn exec_build<P: AsRef<Path>> (
         self,
         pkg_store_dir: P
-    ) -> Result<PKG<'a>, BuildError> {
-        let abs_dir: PathBuf;
-        // Be careful editing this. There are unwraps that rely on
-        // pkg_store_dir and its derivatives being absolute.
-        let pkg_store_dir = if pkg_store_dir.as_ref().is_absolute() {
-            pkg_store_dir.as_ref()
-        } else {
-            abs_dir = pkg_store_dir.as_ref().canonicalize().map_err(
-                |e| BuildError::CanonicalizeError {
-                    err: e,
-                    path: pkg_store_dir.as_ref().into()
-            })?;
-            abs_dir.as_ref()
+    ) -> Result<BuildReport<'a>, BuildError> {
+        self.check_output_names().map_err(BuildError::SetupError)?;
+        let pkg_store_dir = &absolute_store_dir(pkg_store_dir.as_ref())?;
+        // Unwraps are fine, these are the package's outputs
+        let _lock = store::BuildLock::acquire(pkg_store_dir, self.pkg_info.output_names()
+            .map(|o| self.pkg_info.output_ident(o).unwrap())
+        ).map_err(BuildError::LockError)?;
+        let installed: Vec<_> = self.pkg_info.output_names()
+            .map(|o| (o, pkg_store_dir.join(self.pkg_info.output_ident(o).unwrap())))
+            .collect();
+        if installed.iter().all(|(_, dir)| dir.exists()) {
+            // Outputs that are already registered keep the references found
+            // when they were built, which were allowed then, so only the
+            // others are scanned
+            let mut recorded = HashMap::new();
+            let mut unrecorded = Vec::new();
+            for (output, dir) in &installed {
+                self.verify_build_hash(output, dir)?;
+                let ident = self.pkg_info.output_ident(output).unwrap();
+                match store::read_record(pkg_store_dir, &ident)
+                    .map_err(BuildError::RegisterError)?
+                {
+                    Some(record) => { recorded.insert(*output, record.refs); }
+                    None => unrecorded.push((*output, dir.as_path())),
+                }
+            }
+            let mut found = self.find_refs(unrecorded, true)?;
+            found.by_output.extend(recorded);
+            store::register(pkg_store_dir, &self.pkg_info, &found.by_output).map_err(
+                BuildError::RegisterError)?;
+            return Ok(BuildReport{
+                pkg_info: self.pkg_info,
+                usage: None,
+                undeclared_refs: found.undeclared
+            });
+        }
+        let mut guard = self.prepare_context_dir(pkg_store_dir).map_err(
+            |e| BuildError::SetupError(e.into()))?;
+        let build_dir = guard.dir().clone();
+        let out_dirs = match self.setup_out_dirs(pkg_store_dir, &mut guard, OutDirMode::Install) {
+            Ok(ods) => ods,
+            Err(e) => return Err(teardown_after(guard, BuildError::SetupError(e))),
+        };
+        let res = self.apply_patches(&build_dir)
+            .and_then(|_| self.run_build_cmd(pkg_store_dir, &build_dir, &out_dirs))
+            .and_then(|usage| {
+                for out_dir in &out_dirs {
+                    self.check_build_hash(out_dir.name, &out_dir.dir).map_err(
+                        |err| BuildError::HashError{err, teardown_err: None})?;
+                }
+                let found = self.find_refs(
+                    out_dirs.iter().map(|o| (o.name, o.dir.as_path())), true)?;
+                Ok((usage, found))
+            });
+        let (usage, found) = match res {
+            Ok(built) => built,
+            Err(e) => return Err(self.fail_build(guard, e)),
         };
-        let out_dir: PathBuf;
+        // Read-only before it's registered, so that a package is never
+        // registered without being finished
+        let res = out_dirs.iter()
+            .filter(|o| o.dir == o.seen_as)
+            .try_for_each(|o| dirs::set_readonly_all(&o.dir, true))
+            .map_err(|e| BuildError::TeardownError(e.into()))
+            .and_then(|_| store::register(
+                pkg_store_dir, &self.pkg_info, &found.by_output
+            ).map_err(BuildError::RegisterError));
+        if let Err(e) = res {
+            return Err(teardown_after(guard, e));
+        }
+        guard.finish().map_err(BuildError::TeardownError)?;
+        // The outputs that were already installed were only built to be
+        // checked
+        for out_dir in out_dirs.iter().filter(|o| o.dir != o.seen_as) {
+            remove_scratch_dir(&out_dir.dir)?;
+        }
+        Ok(BuildReport{
+            pkg_info: self.pkg_info,
+            usage: Some(usage),
+            undeclared_refs: found.undeclared
+        })
+    }
 
-        let build_dir = self.prepare_context_dir(&pkg_store_dir).map_err(
+    /// Builds the package without checking its output hashes, for when they
+    /// aren't known yet. Each output goes to a scratch directory in the
+    /// package store, and is hashed with the algorithm of its current hash
+    /// and [DirHashVersion::V1], whatever `hash_version` the package had. The
+    /// package is returned with its hashes and `hash_version` replaced by the
+    /// ones used.
+    ///
+    /// If `install` is set, the outputs are then moved to where
+    /// [BuildCxt::exec_build] would have put them. This is only correct if
+    /// the outputs don't refer to their own paths, since those were the
+    /// scratch directories during the build. An output that is already
+    /// installed is kept, but has to match the hash found, or nothing is
+    /// installed. Otherwise the outputs are deleted.
+    pub fn discover_hash<P: AsRef<Path>> (
+        mut self,
+        pkg_store_dir: P,
+        install: bool
+    ) -> Result<BuildReport<'a>, BuildError> {
+        self.check_output_names().map_err(BuildError::SetupError)?;
+        // The old scheme is only there for hashes that were found with it
+        self.pkg_info.set_hash_version(DirHashVersion::V1);
+        let pkg_store_dir = &absolute_store_dir(pkg_store_dir.as_ref())?;
+        let mut guard = self.prepare_context_dir(pkg_store_dir).map_err(
             |e| BuildError::SetupError(e.into()))?;
-        match self.setup_out_dir(&pkg_store_dir, &build_dir) {
-            Ok(od) => {
-                out_dir = od;
+        let build_dir = guard.dir().clone();
+        let out_dirs = match self.setup_out_dirs(pkg_store_dir, &mut guard, OutDirMode::Scratch) {
+            Ok(ods) => ods,
+            Err(e) => return Err(teardown_after(guard, BuildError::SetupError(e))),
+        };
+        let res = self.apply_patches(&build_dir)
+            .and_then(|_| self.run_build_cmd(pkg_store_dir, &build_dir, &out_dirs))
+            .and_then(|usage| {
+                let hashes = out_dirs.iter()
+                    .map(|o| self.hash_output(o.name, &o.dir))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let found = self.find_refs(
+                    out_dirs.iter().map(|o| (o.name, o.dir.as_path())), false)?;
+                Ok((usage, hashes, found))
+            });
+        let (usage, hashes, found) = match res {
+            Ok(found) => found,
+            Err(e) => return Err(self.fail_build(guard, e)),
+        };
+
+        for (out_dir, hash) in out_dirs.iter().zip(hashes) {
+            self.pkg_info.set_output_hash(out_dir.name, hash);
+        }
+        guard.finish().map_err(BuildError::TeardownError)?;
+
+        if !install {
+            for out_dir in &out_dirs {
+                remove_scratch_dir(&out_dir.dir)?;
             }
-            Err(InnerBuildError::MaybeAlreadyInstalled(id)) => {
-                out_dir = pkg_store_dir.join(id);
-                return self.verify_build_hash(&out_dir).and(Ok(self.pkg_info));
+            return Ok(BuildReport{
+                pkg_info: self.pkg_info,
+                usage: Some(usage),
+                undeclared_refs: found.undeclared
+            });
+        }
+        // Unwrap is fine, these are the package's outputs
+        let _lock = store::BuildLock::acquire(pkg_store_dir, self.pkg_info.output_names()
+            .map(|o| self.pkg_info.output_ident(o).unwrap())
+        ).map_err(BuildError::LockError)?;
+        // Unwrap is fine, the names are the package's outputs
+        let final_dir = |out_dir: &OutDir| pkg_store_dir.join(
+            self.pkg_info.output_ident(out_dir.name).unwrap());
+        // An output that is already installed has to be what was just built,
+        // or the hash found would be registered for something else
+        let installed = out_dirs.iter()
+            .filter(|o| final_dir(o).exists())
+            .try_for_each(|o| self.check_build_hash(o.name, &final_dir(o)));
+        if let Err(err) = installed {
+            for out_dir in &out_dirs {
+                remove_scratch_dir(&out_dir.dir)?;
             }
-            Err(e) => { return Err(BuildError::SetupError(e)); }
+            return Err(BuildError::HashError{err, teardown_err: None});
         }
-        self.exec_build_cmd(&pkg_store_dir, &build_dir, &out_dir)?;
-        self.verify_build_hash(&out_dir)?;
-        self.cleanup_post_build(&pkg_store_dir, &build_dir, &out_dir).map_err(
-            |e| BuildError::TeardownError(e))?;
-        Ok(self.pkg_info)
+        for out_dir in &out_dirs {
+            let final_dir = final_dir(out_dir);
+            if final_dir.exists() {
+                remove_scratch_dir(&out_dir.dir)?;
+                continue;
+            }
+            fs::rename(&out_dir.dir, &final_dir).map_err(
+                |e| BuildError::InstallError{err: e, path: final_dir.clone()})?;
+            dirs::set_readonly_all(&final_dir, true).map_err(
+                |e| BuildError::TeardownError(e.into()))?;
+        }
+        store::register(pkg_store_dir, &self.pkg_info, &found.by_output).map_err(
+            BuildError::RegisterError)?;
+        Ok(BuildReport{
+            pkg_info: self.pkg_info,
+            usage: Some(usage),
+            undeclared_refs: found.undeclared
+        })
+    }
+
+    // Hashes `dir` as `output` is hashed
+    fn hash_output(&self, output: &str, dir: &Path) -> Result<hashes::ItemHash, BuildError> {
+        // Unwrap is fine, output is one of the package's
+        let mut hasher = self.pkg_info.output_hash(output).unwrap().algo().hasher();
+        walk_dir::calculate_directory_hash(dir, self.pkg_info.hash_version, &mut hasher)
+            .map_err(|e| BuildError::HashError{err: e.into(), teardown_err: None})?;
+        Ok(hasher.finish())
+    }
+
+    // Compares each rebuilt output with the installed one it was built as
+    fn compare_outputs(&self, out_dirs: &[OutDir]) -> Result<Vec<OutputCheck>, BuildError> {
+        let mut checks = Vec::new();
+        for out_dir in out_dirs {
+            // As it would be if it were installed, so that modes compare
+            dirs::set_readonly_all(&out_dir.dir, true).map_err(BuildError::CompareError)?;
+            let installed_hash = self.hash_output(out_dir.name, &out_dir.seen_as)?;
+            let rebuilt_hash = self.hash_output(out_dir.name, &out_dir.dir)?;
+            let diffs = if installed_hash == rebuilt_hash {
+                Vec::new()
+            } else {
+                dir_diff::compare_dirs(&out_dir.seen_as, &out_dir.dir).map_err(
+                    BuildError::CompareError)?
+            };
+            checks.push(OutputCheck{
+                // Unwrap is fine, the name is one of the package's outputs
+                ident: self.pkg_info.output_ident(out_dir.name).unwrap(),
+                installed_hash,
+                rebuilt_hash,
+                diffs
+            });
+        }
+        Ok(checks)
+    }
+
+    /// Builds an installed package again, with each output going to a
+    /// scratch directory that the build sees where the output is installed,
+    /// and compares the result with what is installed, to tell whether the
+    /// package builds reproducibly. Where the hash of an output differs, the
+    /// [CheckReport] lists the files that differ. Nothing installed is
+    /// changed.
+    pub fn check_build<P: AsRef<Path>> (
+        self,
+        pkg_store_dir: P
+    ) -> Result<CheckReport<'a>, BuildError> {
+        self.check_output_names().map_err(BuildError::SetupError)?;
+        let pkg_store_dir = &absolute_store_dir(pkg_store_dir.as_ref())?;
+        for output in self.pkg_info.output_names() {
+            // Unwrap is fine, output is one of the package's
+            let dir = pkg_store_dir.join(self.pkg_info.output_ident(output).unwrap());
+            if !dir.exists() {
+                return Err(BuildError::NotInstalled(dir));
+            }
+        }
+        let mut guard = self.prepare_context_dir(pkg_store_dir).map_err(
+            |e| BuildError::SetupError(e.into()))?;
+        let build_dir = guard.dir().clone();
+        let out_dirs = match self.setup_out_dirs(pkg_store_dir, &mut guard, OutDirMode::Rebuild) {
+            Ok(ods) => ods,
+            Err(e) => return Err(teardown_after(guard, BuildError::SetupError(e))),
+        };
+        let res = self.apply_patches(&build_dir)
+            .and_then(|_| self.run_build_cmd(pkg_store_dir, &build_dir, &out_dirs));
+        let usage = match res {
+            Ok(usage) => usage,
+            Err(e) => return Err(self.fail_build(guard, e)),
+        };
+        guard.finish().map_err(BuildError::TeardownError)?;
+
+        let res = self.compare_outputs(&out_dirs);
+        for out_dir in &out_dirs {
+            remove_scratch_dir(&out_dir.dir)?;
+        }
+        Ok(CheckReport{pkg_info: self.pkg_info, usage, outputs: res?})
+    }
+}
+
+// Removes an output directory that isn't to be installed
+fn remove_scratch_dir(dir: &Path) -> Result<(), BuildError> {
+    dirs::set_writable_dirs(dir)
+        .and_then(|_| fs::remove_dir_all(dir))
+        .map_err(|e| BuildError::TeardownError(e.into()))
+}
+
+// Lists references from outputs, as in BuildError::UndeclaredRefs
+fn list_refs(refs: &[(String, String)]) -> String {
+    let refs: Vec<_> = refs.iter()
+        .map(|(output, r)| format!("{} (from output {})", r, output))
+        .collect();
+    refs.join(", ")
+}
+
+// Tears down `guard` after a failure with `err`, reporting any error in doing
+// so along with it
+fn teardown_after(guard: ContextGuard, err: BuildError) -> BuildError {
+    match guard.teardown() {
+        Ok(()) => err,
+        Err(teardown_err) => BuildError::FailedTeardown{
+            err: Box::new(err),
+            teardown_err
+        },
+    }
+}
+
+// Be careful editing this. There are unwraps that rely on pkg_store_dir and
+// its derivatives being absolute.
+fn absolute_store_dir(pkg_store_dir: &Path) -> Result<PathBuf, BuildError> {
+    if pkg_store_dir.is_absolute() {
+        Ok(pkg_store_dir.to_path_buf())
+    } else {
+        pkg_store_dir.canonicalize().map_err(
+            |e| BuildError::CanonicalizeError {
+                err: e,
+                path: pkg_store_dir.into()
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use blake2::Digest;
+    use std::env;
+    use std::process;
+    use blake2::{Blake2s, Digest};
 
     fn example_buildcxt() -> BuildCxt<'static> {
         let mut new = BuildCxt::new(
@@ -300,10 +949,57 @@ mod tests {
         new
     }
 
+    #[test]
+    fn test_failed_prepare() {
+        use std::str::FromStr;
+
+        let name = format!("prepfail{}", process::id());
+        let mut cxt = BuildCxt::new(&name, "1.0.0", Blake2s::digest(b"").into(), "./build.sh");
+        let missing = url::Url::from_str("file:///nonexistent/yafpm-src").unwrap();
+        cxt.add_srcs([RS::new("src", Blake2s::digest(b"").into(), missing)]);
+        assert!(cxt.prepare_context_dir(&env::temp_dir()).is_err());
+        // The root directory was torn down again
+        let prefix = format!("{}-build-", name);
+        assert!(!fs::read_dir(env::temp_dir()).unwrap()
+            .any(|e| e.unwrap().file_name().to_string_lossy().starts_with(&prefix)));
+    }
+
+    #[test]
+    fn test_rerun_installed() {
+        use crate::hashes::HashAlgo;
+
+        let store = env::temp_dir().join(format!("yafpm-rerun-test-{}", process::id()));
+        let tool = PKG::new("tool", "1.0.0", Blake2s::digest(b"tool").into());
+        let tool_ident = tool.pkg_ident();
+        let contents = format!("built with {}\n", tool_ident);
+        let scratch = store.join("scratch");
+        fs::create_dir_all(&scratch).unwrap();
+        fs::write(scratch.join("file"), &contents).unwrap();
+        let mut hasher = HashAlgo::Blake2s.hasher();
+        walk_dir::calculate_directory_hash(&scratch, DirHashVersion::V1, &mut hasher)
+            .unwrap();
+        let mut cxt = BuildCxt::new("rerun", "1.0.0", hasher.finish(), "./build.sh");
+        cxt.pkg_info.set_hash_version(DirHashVersion::V1);
+        cxt.add_build_deps([tool]);
+        let ident = cxt.pkg_info.pkg_ident();
+        fs::rename(&scratch, store.join(&ident)).unwrap();
+        let mut refs = HashMap::new();
+        refs.insert("out", vec![tool_ident.clone()]);
+        store::register(&store, &cxt.pkg_info, &refs).unwrap();
+
+        // The reference to the build dependency was allowed when the package
+        // was built, and running the build again doesn't need that again
+        let report = cxt.exec_build(&store).unwrap();
+        assert!(report.usage.is_none());
+        let record = store::read_record(&store, &ident).unwrap().unwrap();
+        assert_eq!(record.refs, [tool_ident]);
+        fs::remove_dir_all(store).unwrap();
+    }
+
     #[test]
     fn test_make_path_string() {
         let ex = example_buildcxt();
-        let s = ex.make_path_string("/root");
+        let s = ex.make_path_string(Path::new("/root"));
         assert_eq!(
             s,
             "/root/dependency-1.0.0-GNC4RH2YRCDAH7AHVIISWYE2JSD3PJXAQTRCMTGQLXJRULOJKI5A/bin/:"
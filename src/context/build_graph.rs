@@ -0,0 +1,193 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+// 
+// Copyright (C) 2021 John Arnold
+//
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use std::error::Error;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use url::Url;
+
+use super::BuildCxt;
+
+#[derive(Debug, thiserror::Error)]
+/// The error returned by [build_order].
+pub enum BuildGraphError {
+    #[error("Dependency cycle: {}", .0.join(" -> "))]
+    CycleError(Vec<String>),
+    #[error("{0} is not installed and does not name a recipe")]
+    MissingRecipeError(String),
+    #[error("Unable to load recipe {url}")]
+    LoadError {
+        url: Box<Url>,
+        #[source]
+        err: Box<dyn Error>,
+    },
+    #[error("Recipe {url} builds {found} instead of {expected}")]
+    MismatchError {
+        url: Box<Url>,
+        expected: String,
+        found: String,
+    },
+}
+
+struct GraphWalk<'a, F> {
+    pkg_store_dir: PathBuf,
+    load: F,
+    // Package identifiers on the path from the root to the current node,
+    // used to spot cycles.
+    stack: Vec<String>,
+    done: HashSet<String>,
+    order: Vec<(Url, BuildCxt<'a>)>,
+}
+
+impl<'a, F> GraphWalk<'a, F>
+    where F: FnMut(&Url) -> Result<BuildCxt<'a>, Box<dyn Error>>
+{
+    fn visit(&mut self, cxt: &BuildCxt<'_>) -> Result<(), BuildGraphError> {
+        // Collect first so that cxt isn't borrowed while we recurse
+        let deps: Vec<(String, Option<Url>)> = cxt.all_deps()
+            .filter(|d| !d.is_installed(&mut self.pkg_store_dir))
+            .map(|d| (d.pkg_ident(), d.recipe.clone()))
+            .collect();
+        for (ident, recipe) in deps {
+            if self.done.contains(&ident) {
+                continue;
+            }
+            if let Some(pos) = self.stack.iter().position(|i| *i == ident) {
+                let mut cycle = self.stack.split_off(pos);
+                cycle.push(ident);
+                return Err(BuildGraphError::CycleError(cycle));
+            }
+            let url = recipe.ok_or_else(
+                || BuildGraphError::MissingRecipeError(ident.clone()))?;
+            let dep_cxt = (self.load)(&url).map_err(
+                |e| BuildGraphError::LoadError{url: Box::new(url.clone()), err: e})?;
+            // The dependency may be any output of the recipe's package
+            let outputs: Vec<String> = dep_cxt.pkg_info.output_names()
+                .filter_map(|o| dep_cxt.pkg_info.output_ident(o))
+                .collect();
+            if !outputs.contains(&ident) {
+                return Err(BuildGraphError::MismatchError{
+                    url: Box::new(url),
+                    expected: ident,
+                    found: dep_cxt.pkg_info.pkg_ident()
+                });
+            }
+            self.stack.push(ident);
+            self.visit(&dep_cxt)?;
+            self.stack.pop();
+            // Building it builds every output, so none needs building again
+            self.done.extend(outputs);
+            self.order.push((url, dep_cxt));
+        }
+        Ok(())
+    }
+}
+
+/// Works out which dependencies of `root` need to be built, and in what order.
+///
+/// Any dependency (or build dependency) that is not already installed in
+/// `pkg_store_dir` must name a recipe, which is handed to `load` to get its
+/// [BuildCxt]. The result lists each such recipe together with its context,
+/// with every package coming after all the packages it depends on. `root`
+/// itself is not included.
+///
+/// Note that [BuildCxt::exec_build] sets up new namespaces for the calling
+/// process, so a single process can only build one package. `yafpm-build`
+/// deals with this by running a child `yafpm-build` for each dependency.
+pub fn build_order<'a, F>(
+    root: &BuildCxt<'_>,
+    pkg_store_dir: &Path,
+    load: F,
+) -> Result<Vec<(Url, BuildCxt<'a>)>, BuildGraphError>
+    where F: FnMut(&Url) -> Result<BuildCxt<'a>, Box<dyn Error>>
+{
+    let mut walk = GraphWalk {
+        pkg_store_dir: pkg_store_dir.to_path_buf(),
+        load,
+        stack: vec![root.pkg_info.pkg_ident()],
+        done: HashSet::new(),
+        order: Vec::new(),
+    };
+    walk.visit(root)?;
+    Ok(walk.order)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use blake2::{Blake2s, Digest};
+    use crate::package::{Output, Package as PKG};
+
+    fn example_cxt(name: &'static str, deps: &[&'static str]) -> BuildCxt<'static> {
+        let mut cxt = BuildCxt::new(
+            name,
+            "1.0.0",
+            Blake2s::digest(name.as_bytes()).into(),
+            "./build.sh"
+        );
+        cxt.add_pkg_deps(deps.iter().map(|d| {
+            let mut dep = PKG::new(d, "1.0.0", Blake2s::digest(d.as_bytes()).into());
+            dep.set_recipe(Url::from_str(&format!("file:///{}.toml", d)).unwrap());
+            dep
+        }));
+        cxt
+    }
+
+    fn loader(url: &Url) -> Result<BuildCxt<'static>, Box<dyn Error>> {
+        match url.path() {
+            "/a.toml" => Ok(example_cxt("a", &["b", "c"])),
+            "/b.toml" => Ok(example_cxt("b", &["c"])),
+            "/c.toml" => Ok(example_cxt("c", &[])),
+            "/d.toml" => Ok(example_cxt("d", &["e"])),
+            "/e.toml" => Ok(example_cxt("e", &["d"])),
+            "/f.toml" | "/f.dev.toml" => {
+                let mut cxt = example_cxt("f", &["c"]);
+                cxt.pkg_info.add_outputs(
+                    Some(Output::new("dev", Blake2s::digest(b"f.dev").into())));
+                Ok(cxt)
+            }
+            _ => Err("no such recipe".into()),
+        }
+    }
+
+    #[test]
+    fn test_build_order() {
+        let root = example_cxt("root", &["a", "c"]);
+        let order = build_order(&root, Path::new("/nonexistent"), loader).unwrap();
+        let names: Vec<_> = order.iter().map(|(_, c)| c.pkg_info.pkg_name).collect();
+        assert_eq!(names, ["c", "b", "a"]);
+    }
+
+    #[test]
+    fn test_build_order_cycle() {
+        let root = example_cxt("root", &["d"]);
+        let res = build_order(&root, Path::new("/nonexistent"), loader);
+        match res {
+            Err(BuildGraphError::CycleError(cycle)) => assert_eq!(cycle.len(), 3),
+            _ => panic!("cycle not detected"),
+        }
+    }
+
+    #[test]
+    fn test_build_order_outputs() {
+        let root = example_cxt("root", &["f.dev", "f"]);
+        let order = build_order(&root, Path::new("/nonexistent"), loader).unwrap();
+        let names: Vec<_> = order.iter().map(|(_, c)| c.pkg_info.pkg_name).collect();
+        assert_eq!(names, ["c", "f"]);
+    }
+}
@@ -0,0 +1,163 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+// 
+// Copyright (C) 2021 John Arnold
+//
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The [ContextGuard], which owns what setting up a context leaves behind, so
+//! that it's undone however the build or shell ends.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::dirs;
+use crate::namespace;
+
+#[derive(Debug, thiserror::Error)]
+/// An error while undoing what a context set up.
+pub enum TeardownError {
+    #[error(transparent)]
+    IOError(#[from] io::Error),
+    #[error(transparent)]
+    NSError(#[from] namespace::NSError),
+}
+
+/// The root directory of a context, along with everything mounted in it and
+/// the output directories in the store, if there are any. Dropping the guard
+/// tears all of it down, ignoring errors; [ContextGuard::teardown] does the
+/// same but reports them.
+pub(crate) struct ContextGuard {
+    dir: PathBuf,
+    // Everything mounted in dir, in the order it was mounted
+    mounts: Vec<PathBuf>,
+    // Each output directory, with the path the build sees it at
+    out_dirs: Vec<(PathBuf, PathBuf)>,
+    done: bool,
+}
+
+impl ContextGuard {
+    pub(crate) fn new(dir: PathBuf) -> Self {
+        ContextGuard { dir, mounts: Vec::new(), out_dirs: Vec::new(), done: false }
+    }
+
+    pub(crate) fn dir(&self) -> &PathBuf {
+        &self.dir
+    }
+
+    /// The list of mounts to pass to the functions of [namespace] that
+    /// mount something in the root directory.
+    pub(crate) fn mounts_mut(&mut self) -> &mut Vec<PathBuf> {
+        &mut self.mounts
+    }
+
+    /// Makes the guard responsible for removing `out_dir` too, unless the
+    /// build succeeds. The build sees it at `seen_as`.
+    pub(crate) fn add_out_dir(&mut self, out_dir: PathBuf, seen_as: PathBuf) {
+        self.out_dirs.push((out_dir, seen_as));
+    }
+
+    // The root directory must never be removed while something is still
+    // mounted in it, or the removal would reach into what is mounted.
+    fn remove_dir(&mut self) -> Result<(), TeardownError> {
+        namespace::umount_all(&mut self.mounts)?;
+        fs::remove_dir_all(&self.dir)?;
+        Ok(())
+    }
+
+    fn remove_out_dirs(&mut self) -> Result<(), TeardownError> {
+        let mut res = Ok(());
+        for (out_dir, _) in self.out_dirs.drain(..) {
+            let removed = dirs::set_writable_dirs(&out_dir)
+                .and_then(|_| fs::remove_dir_all(&out_dir));
+            res = res.and(removed);
+        }
+        Ok(res?)
+    }
+
+    fn teardown_inner(&mut self) -> Result<(), TeardownError> {
+        self.done = true;
+        let res = self.remove_dir();
+        // Whatever became of the root directory, the output in the store is
+        // unfinished and has to go
+        self.remove_out_dirs().and(res)
+    }
+
+    /// Tears down everything, including the output directories.
+    pub(crate) fn teardown(mut self) -> Result<(), TeardownError> {
+        self.teardown_inner()
+    }
+
+    /// Tears down the root directory, but leaves the output directories in
+    /// place, for when the build has succeeded.
+    pub(crate) fn finish(mut self) -> Result<(), TeardownError> {
+        self.done = true;
+        self.remove_dir()
+    }
+
+    /// Unmounts everything, and moves what was written to each output
+    /// directory to where the build saw it in the root directory, which is
+    /// kept. Returns the root directory. If this fails, the guard is left
+    /// for tearing down.
+    pub(crate) fn keep(&mut self) -> Result<PathBuf, TeardownError> {
+        namespace::umount_all(&mut self.mounts)?;
+        for (out_dir, seen_as) in &self.out_dirs {
+            // This should be safe because seen_as is in the absolute store dir
+            let kept_out = self.dir.join(seen_as.strip_prefix("/").unwrap());
+            copy_to_mount_point(out_dir, &kept_out)?;
+        }
+        self.remove_out_dirs()?;
+        self.done = true;
+        Ok(self.dir.clone())
+    }
+}
+
+// Copies `src` to the now unmounted mount point `target`
+fn copy_to_mount_point(src: &Path, target: &Path) -> Result<(), io::Error> {
+    // The mount point is gone if it was in a tmpfs that was mounted over
+    match fs::remove_dir(target) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    dirs::copy_dir_all(src, target)
+}
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = self.teardown_inner();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guard_drop() {
+        let temp_dir = std::env::temp_dir();
+        let dir = dirs::create_context_dir("guard-test-build").unwrap();
+        let out_dir = dirs::create_outdir(&temp_dir, "guard-test-out").unwrap();
+        fs::write(dir.join("file"), "contents").unwrap();
+        let mut guard = ContextGuard::new(dir.clone());
+        guard.add_out_dir(out_dir.clone(), out_dir.clone());
+        drop(guard);
+        assert!(!dir.exists());
+        assert!(!out_dir.exists());
+    }
+}
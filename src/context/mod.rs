@@ -16,21 +16,30 @@
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
 mod build_cxt;
+mod build_graph;
+mod guard;
+mod monitor;
 mod shell_cxt;
-pub use build_cxt::{BuildCxt, BuildError};
+pub use build_cxt::{BuildCxt, BuildError, BuildReport, CheckReport, OutputCheck};
+pub use build_graph::{build_order, BuildGraphError};
+pub use guard::TeardownError;
 pub use shell_cxt::{ShellCxt, ShellError};
 
 use std::io;
 use std::ffi::OsString;
-use std::path::{Path, PathBuf};
+use std::path::Path;
 
 use crate::dirs;
+use guard::ContextGuard;
 use crate::namespace;
+use crate::namespace::Sandbox;
 use crate::resource;
 use crate::package::Package as PKG;
 use crate::resource::Resource as RS;
+use crate::resource::FetchOpts;
 
 #[derive(Debug, thiserror::Error)]
+#[allow(clippy::enum_variant_names)]
 pub enum ContextPrepError {
     #[error(transparent)]
     IOError(#[from] io::Error),
@@ -38,6 +47,8 @@ pub enum ContextPrepError {
     NSError(#[from] namespace::NSError),
     #[error(transparent)]
     RSError(#[from] resource::ResourceError),
+    #[error("Unable to tear down what was set up after an error: {teardown_err}")]
+    FailedTeardown{#[source] err: Box<ContextPrepError>, teardown_err: TeardownError},
 }
 
 pub trait Context<'a> {
@@ -50,20 +61,43 @@ pub trait Context<'a> {
 
     fn dependencies(&'a self) -> Self::D;
 
+    fn fetch_opts(&self) -> &FetchOpts;
+
+    fn sandbox(&self) -> Option<&Sandbox<'a>> {
+        None
+    }
+
+    /// Sets up the root directory of the context, which is torn down again
+    /// when the returned guard is dropped. If setting it up fails, whatever
+    /// was set up is torn down before returning.
     fn prepare_context_dir(
         &'a self,
         pkg_store_dir: &Path
-    ) -> Result<PathBuf, ContextPrepError> {
+    ) -> Result<ContextGuard, ContextPrepError> {
         let context_dir = dirs::create_context_dir(&self.context_name())?;
-        for src in self.resources() {
-            src.fetch_resource(&context_dir)?;
+        let mut guard = ContextGuard::new(context_dir.clone());
+        let res = self.resources().into_iter()
+            .try_for_each(|src| src.fetch_resource(&context_dir, self.fetch_opts()))
+            .map_err(ContextPrepError::from)
+            .and_then(|_| Ok(namespace::setup_new_namespace()?))
+            .and_then(|_| match self.sandbox() {
+                Some(sandbox) => Ok(namespace::mount_sandbox(
+                    &context_dir, sandbox, guard.mounts_mut())?),
+                None => Ok(()),
+            })
+            .and_then(|_| Ok(namespace::mount_dep_dirs(
+                pkg_store_dir, &context_dir, self.dependencies(), guard.mounts_mut()
+            )?));
+        match res {
+            Ok(()) => Ok(guard),
+            Err(err) => match guard.teardown() {
+                Ok(()) => Err(err),
+                Err(teardown_err) => Err(ContextPrepError::FailedTeardown{
+                    err: Box::new(err),
+                    teardown_err
+                }),
+            },
         }
-        namespace::setup_new_namespace()?;
-        namespace::mount_dep_dirs(
-            pkg_store_dir, &context_dir, self.dependencies()
-        )?;
-
-        Ok(context_dir)
     }
 
     fn make_path_string(&'a self, pkg_store_dir: &Path) -> OsString {
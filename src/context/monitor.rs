@@ -0,0 +1,158 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+// 
+// Copyright (C) 2021 John Arnold
+//
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use std::io;
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::process::{Child, ExitStatus};
+use std::time::Instant;
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::unistd::read;
+
+use crate::limits::BuildLimits;
+
+/// How a build command watched by [watch] ended.
+pub(super) enum Outcome {
+    Exited(ExitStatus),
+    /// Killed for running too long, or for going too long without output if
+    /// `silent` is set.
+    TimedOut { secs: u64, silent: bool },
+}
+
+fn nix_to_io(err: nix::Error) -> io::Error {
+    match err.as_errno() {
+        Some(errno) => io::Error::from_raw_os_error(errno as i32),
+        None => io::Error::other(err),
+    }
+}
+
+// Milliseconds from now until `deadline`, as poll wants them
+fn millis_until(deadline: Instant, now: Instant) -> i32 {
+    let left = deadline.saturating_duration_since(now);
+    // Rounded up, so that poll doesn't wake just short of the deadline
+    let millis = left.as_nanos().div_ceil(1_000_000);
+    millis.min(i32::MAX as u128) as i32
+}
+
+/// Passes on what `child` prints to its piped stdout and stderr until both
+/// are closed, then waits for it. If `limits` has a timeout that runs out
+/// first, `child` is killed. `child` is the init of the build's PID
+/// namespace, so this takes everything in the namespace with it.
+pub(super) fn watch(
+    child: &mut Child,
+    limits: &BuildLimits
+) -> Result<Outcome, io::Error> {
+    let start = Instant::now();
+    let mut last_output = start;
+    let mut fds = [
+        PollFd::new(child.stdout.as_ref().map_or(-1, |s| s.as_raw_fd()),
+                    PollFlags::POLLIN),
+        PollFd::new(child.stderr.as_ref().map_or(-1, |s| s.as_raw_fd()),
+                    PollFlags::POLLIN),
+    ];
+    let mut open = [child.stdout.is_some(), child.stderr.is_some()];
+    let mut buf = [0; 64 * 1024];
+    while open[0] || open[1] {
+        let now = Instant::now();
+        let deadlines = [
+            limits.timeout().map(|t| (start + t, t, false)),
+            limits.silence_timeout().map(|t| (last_output + t, t, true)),
+        ];
+        let next = deadlines.iter().flatten().min_by_key(|(d, _, _)| *d);
+        if let Some(&(deadline, limit, silent)) = next {
+            if deadline <= now {
+                child.kill()?;
+                child.wait()?;
+                return Ok(Outcome::TimedOut { secs: limit.as_secs(), silent });
+            }
+        }
+        let wait = next.map_or(-1, |(d, _, _)| millis_until(*d, now));
+        match poll(&mut fds, wait) {
+            Ok(_) => (),
+            Err(nix::Error::Sys(Errno::EINTR)) => continue,
+            Err(e) => return Err(nix_to_io(e)),
+        }
+        for i in 0..fds.len() {
+            let ready = fds[i].revents().is_some_and(|r| !r.is_empty());
+            if !open[i] || !ready {
+                continue;
+            }
+            let fd = if i == 0 {
+                child.stdout.as_ref().map(|s| s.as_raw_fd())
+            } else {
+                child.stderr.as_ref().map(|s| s.as_raw_fd())
+            };
+            // Unwrap is fine, open is only set for the pipes we have
+            let n = match read(fd.unwrap(), &mut buf) {
+                Ok(n) => n,
+                Err(nix::Error::Sys(Errno::EINTR)) => continue,
+                Err(e) => return Err(nix_to_io(e)),
+            };
+            if n == 0 {
+                open[i] = false;
+                fds[i] = PollFd::new(-1, PollFlags::POLLIN);
+                continue;
+            }
+            last_output = Instant::now();
+            if i == 0 {
+                io::stdout().write_all(&buf[..n])?;
+            } else {
+                io::stderr().write_all(&buf[..n])?;
+            }
+        }
+    }
+    Ok(Outcome::Exited(child.wait()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::{Command, Stdio};
+
+    fn sh(script: &str) -> Child {
+        Command::new("/bin/sh")
+            .args(["-c", script])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_watch() {
+        let mut limits = BuildLimits::new();
+        limits.set_timeout(1);
+        let mut child = sh("echo out; echo err >&2; exit 3");
+        match watch(&mut child, &limits).unwrap() {
+            Outcome::Exited(status) => assert_eq!(status.code(), Some(3)),
+            _ => panic!("timed out"),
+        }
+        let mut child = sh("exec sleep 5");
+        match watch(&mut child, &limits).unwrap() {
+            Outcome::TimedOut { secs: 1, silent: false } => (),
+            _ => panic!("no timeout"),
+        }
+        let mut limits = BuildLimits::new();
+        limits.set_timeout(5).set_silence_timeout(1);
+        let mut child = sh("echo start; exec sleep 5");
+        match watch(&mut child, &limits).unwrap() {
+            Outcome::TimedOut { secs: 1, silent: true } => (),
+            _ => panic!("no silence timeout"),
+        }
+    }
+}
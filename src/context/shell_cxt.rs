@@ -15,30 +15,22 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
-use std::fs;
 use std::io;
 use std::slice::Iter;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::os::unix::process::CommandExt;
-use nix::unistd::chroot;
 
 use super::Context;
+use super::guard::TeardownError;
 use crate::namespace;
 use crate::resource::Resource as RS;
+use crate::resource::FetchOpts;
 use crate::package::Package as PKG;
 
 #[cfg(feature = "serde")]
 use serde::{Serialize, Deserialize};
 
-#[derive(Debug, thiserror::Error)]
-pub enum InnerShellError {
-    #[error(transparent)]
-    IOError(#[from] io::Error),
-    #[error(transparent)]
-    NSError(#[from] namespace::NSError),
-}
-
 #[derive(Debug, thiserror::Error)]
 /// The error returned by [ShellCxt].
 pub enum ShellError {
@@ -49,7 +41,9 @@ pub enum ShellError {
     #[error("Unable to execute shell command")]
     ExecCmdError(#[source] io::Error),
     #[error("Error while tearing down shell environment")]
-    TeardownError(#[from] InnerShellError)
+    TeardownError(#[from] TeardownError),
+    #[error("Shell failed, and then its environment could not be torn down")]
+    FailedTeardown{#[source] err: Box<ShellError>, teardown_err: TeardownError},
 }
 
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
@@ -66,6 +60,8 @@ pub struct ShellCxt<'a> {
     #[cfg_attr(feature = "serde", serde(rename = "shell_command"))]
     #[cfg_attr(feature = "serde", serde(alias = "build_command"))]
     shell_cmd: &'a str,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    fetch_opts: FetchOpts,
 }
 
 impl<'a> Context<'a> for ShellCxt<'a> {
@@ -87,6 +83,10 @@ impl<'a> Context<'a> for ShellCxt<'a> {
     fn dependencies(&'a self) -> Self::D {
         self.shell_deps.iter()
     }
+
+    fn fetch_opts(&self) -> &FetchOpts {
+        &self.fetch_opts
+    }
 }
 
 impl<'a> ShellCxt<'a> {
@@ -94,7 +94,8 @@ impl<'a> ShellCxt<'a> {
         ShellCxt {
             resources: Vec::new(),
             shell_deps: Vec::new(),
-            shell_cmd
+            shell_cmd,
+            fetch_opts: FetchOpts::default(),
         }
     }
 
@@ -117,6 +118,11 @@ impl<'a> ShellCxt<'a> {
         self
     }
 
+    pub fn set_fetch_opts(&mut self, opts: FetchOpts) -> &mut Self {
+        self.fetch_opts = opts;
+        self
+    }
+
     fn exec_shell_cmd(
         &'a self,
         pkg_store_dir: &Path,
@@ -129,11 +135,11 @@ impl<'a> ShellCxt<'a> {
              .envs(self.dependencies().map(dep_env_clos))
              .env("PATH", self.make_path_string(pkg_store_dir))
              .current_dir(context_dir);
-        // TODO there has to be an more elegant way of doing this
-        let build_dir_clone = context_dir.clone();
+        let new_root = namespace::NewRoot::new(context_dir, false)
+            .map_err(ShellError::ExecCmdError)?;
         unsafe {
             child.pre_exec(move || {
-                let res = chroot(&build_dir_clone);
+                let res = new_root.enter();
                 res.map_err(|e| if let Some(errno) = e.as_errno() {
                     io::Error::from_raw_os_error(errno as i32)
                 } else {
@@ -142,25 +148,13 @@ impl<'a> ShellCxt<'a> {
             });
         }
         child.status().map_err(
-            |e| ShellError::ExecCmdError(e)
+            ShellError::ExecCmdError
         )?;
 
         Ok(())
 
     }
 
-    fn teardown_shell(
-        &self,
-        pkg_store_dir: &Path,
-        build_dir: &PathBuf,
-    ) -> Result<(), InnerShellError> {
-        namespace::umount_dep_dirs(&pkg_store_dir.as_ref(),
-                                   &build_dir,
-                                   self.dependencies())?;
-        fs::remove_dir_all(&build_dir)?;
-        Ok(())
-    }
-
     pub fn enter_shell<P: AsRef<Path>> (
         self,
         pkg_store_dir: P
@@ -178,11 +172,17 @@ impl<'a> ShellCxt<'a> {
             })?;
             abs_dir.as_ref()
         };
-        let context_dir = self.prepare_context_dir(&pkg_store_dir).map_err(
-            |e| ShellError::SetupError(e.into()))?;
-        self.exec_shell_cmd(&pkg_store_dir, &context_dir)?;
-        self.teardown_shell(&pkg_store_dir, &context_dir)?;
-
-        Ok(())
+        let guard = self.prepare_context_dir(pkg_store_dir).map_err(
+            ShellError::SetupError)?;
+        let res = self.exec_shell_cmd(pkg_store_dir, guard.dir());
+        match (res, guard.finish()) {
+            (Ok(()), Ok(())) => Ok(()),
+            (Ok(()), Err(teardown_err)) => Err(teardown_err.into()),
+            (Err(err), Ok(())) => Err(err),
+            (Err(err), Err(teardown_err)) => Err(ShellError::FailedTeardown{
+                err: Box::new(err),
+                teardown_err
+            }),
+        }
     }
 }
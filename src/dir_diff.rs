@@ -0,0 +1,208 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+// 
+// Copyright (C) 2021 John Arnold
+//
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! Comparing an installed output with a rebuild of it, file by file.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A difference between an installed output and a rebuild of it. Paths are
+/// relative to the output directory.
+pub enum FileDiff {
+    OnlyInstalled(PathBuf),
+    OnlyRebuilt(PathBuf),
+    FileType(PathBuf),
+    Size{path: PathBuf, installed: u64, rebuilt: u64},
+    Mode{path: PathBuf, installed: u32, rebuilt: u32},
+    /// The contents first differ at byte `offset`, which is within both.
+    Contents{path: PathBuf, offset: u64},
+    SymlinkTarget{path: PathBuf, installed: PathBuf, rebuilt: PathBuf},
+}
+
+// The output directory itself has an empty relative path
+fn show(path: &Path) -> impl fmt::Display + '_ {
+    if path.as_os_str().is_empty() { Path::new(".") } else { path }.display()
+}
+
+impl fmt::Display for FileDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileDiff::OnlyInstalled(path) => {
+                write!(f, "{}: only in installed output", show(path))
+            }
+            FileDiff::OnlyRebuilt(path) => {
+                write!(f, "{}: only in rebuilt output", show(path))
+            }
+            FileDiff::FileType(path) => {
+                write!(f, "{}: file type differs", show(path))
+            }
+            FileDiff::Size{path, installed, rebuilt} => {
+                write!(f, "{}: size {} installed, {} rebuilt",
+                       show(path), installed, rebuilt)
+            }
+            FileDiff::Mode{path, installed, rebuilt} => {
+                write!(f, "{}: mode {:o} installed, {:o} rebuilt",
+                       show(path), installed, rebuilt)
+            }
+            FileDiff::Contents{path, offset} => {
+                write!(f, "{}: contents differ from byte {}", show(path), offset)
+            }
+            FileDiff::SymlinkTarget{path, installed, rebuilt} => {
+                write!(f, "{}: symlink to {} installed, {} rebuilt",
+                       show(path), installed.display(), rebuilt.display())
+            }
+        }
+    }
+}
+
+// The first offset at which the files differ, within the length of both
+fn first_difference(installed: &Path, rebuilt: &Path) -> Result<Option<u64>, io::Error> {
+    let mut installed = BufReader::new(File::open(installed)?);
+    let mut rebuilt = BufReader::new(File::open(rebuilt)?);
+    let mut offset = 0;
+    loop {
+        let a = installed.fill_buf()?;
+        let b = rebuilt.fill_buf()?;
+        let len = a.len().min(b.len());
+        if len == 0 {
+            return Ok(None);
+        }
+        if let Some(i) = a[..len].iter().zip(&b[..len]).position(|(x, y)| x != y) {
+            return Ok(Some(offset + i as u64));
+        }
+        installed.consume(len);
+        rebuilt.consume(len);
+        offset += len as u64;
+    }
+}
+
+fn dir_names(dir: &Path) -> Result<BTreeSet<PathBuf>, io::Error> {
+    fs::read_dir(dir)?
+        .map(|e| e.map(|e| PathBuf::from(e.file_name())))
+        .collect()
+}
+
+fn compare_node(
+    installed_root: &Path,
+    rebuilt_root: &Path,
+    path: &Path,
+    diffs: &mut Vec<FileDiff>
+) -> Result<(), io::Error> {
+    let installed = installed_root.join(path);
+    let rebuilt = rebuilt_root.join(path);
+    let installed_meta = fs::symlink_metadata(&installed)?;
+    let rebuilt_meta = fs::symlink_metadata(&rebuilt)?;
+    let file_type = installed_meta.file_type();
+    if file_type != rebuilt_meta.file_type() {
+        diffs.push(FileDiff::FileType(path.to_path_buf()));
+        return Ok(());
+    }
+    if file_type.is_symlink() {
+        let installed = fs::read_link(&installed)?;
+        let rebuilt = fs::read_link(&rebuilt)?;
+        if installed != rebuilt {
+            diffs.push(FileDiff::SymlinkTarget{
+                path: path.to_path_buf(),
+                installed,
+                rebuilt
+            });
+        }
+        return Ok(());
+    }
+    let installed_mode = installed_meta.permissions().mode() & 0o7777;
+    let rebuilt_mode = rebuilt_meta.permissions().mode() & 0o7777;
+    if installed_mode != rebuilt_mode {
+        diffs.push(FileDiff::Mode{
+            path: path.to_path_buf(),
+            installed: installed_mode,
+            rebuilt: rebuilt_mode
+        });
+    }
+    if file_type.is_dir() {
+        let installed_names = dir_names(&installed)?;
+        let rebuilt_names = dir_names(&rebuilt)?;
+        for name in installed_names.union(&rebuilt_names) {
+            let child = path.join(name);
+            if !rebuilt_names.contains(name) {
+                diffs.push(FileDiff::OnlyInstalled(child));
+            } else if !installed_names.contains(name) {
+                diffs.push(FileDiff::OnlyRebuilt(child));
+            } else {
+                compare_node(installed_root, rebuilt_root, &child, diffs)?;
+            }
+        }
+    } else if file_type.is_file() {
+        if installed_meta.len() != rebuilt_meta.len() {
+            diffs.push(FileDiff::Size{
+                path: path.to_path_buf(),
+                installed: installed_meta.len(),
+                rebuilt: rebuilt_meta.len()
+            });
+        }
+        if let Some(offset) = first_difference(&installed, &rebuilt)? {
+            diffs.push(FileDiff::Contents{path: path.to_path_buf(), offset});
+        }
+    }
+    Ok(())
+}
+
+/// Lists how the tree at `rebuilt` differs from the one at `installed`, in
+/// order of path.
+pub(crate) fn compare_dirs(
+    installed: &Path,
+    rebuilt: &Path
+) -> Result<Vec<FileDiff>, io::Error> {
+    let mut diffs = Vec::new();
+    compare_node(installed, rebuilt, Path::new(""), &mut diffs)?;
+    Ok(diffs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare_dirs() {
+        let root = std::env::temp_dir().join(format!("yafpm-diff-test-{}", std::process::id()));
+        let installed = root.join("installed");
+        let rebuilt = root.join("rebuilt");
+        for dir in [&installed, &rebuilt] {
+            fs::create_dir_all(dir.join("sub")).unwrap();
+            fs::write(dir.join("same"), b"same").unwrap();
+        }
+        fs::write(installed.join("sub/file"), b"abcdef").unwrap();
+        fs::write(rebuilt.join("sub/file"), b"abXdefgh").unwrap();
+        fs::write(installed.join("gone"), b"").unwrap();
+        fs::set_permissions(installed.join("same"), fs::Permissions::from_mode(0o644)).unwrap();
+        fs::set_permissions(rebuilt.join("same"), fs::Permissions::from_mode(0o755)).unwrap();
+        let diffs = compare_dirs(&installed, &rebuilt).unwrap();
+        assert_eq!(diffs, [
+            FileDiff::OnlyInstalled(PathBuf::from("gone")),
+            FileDiff::Mode{path: PathBuf::from("same"), installed: 0o644, rebuilt: 0o755},
+            FileDiff::Size{path: PathBuf::from("sub/file"), installed: 6, rebuilt: 8},
+            FileDiff::Contents{path: PathBuf::from("sub/file"), offset: 2},
+        ]);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
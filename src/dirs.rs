@@ -19,6 +19,11 @@ use std::fs;
 use std::io;
 use std::env;
 use std::path::{Path, PathBuf};
+use filetime::FileTime;
+
+// Fetched resources get this as the modification time of every file, so
+// that nothing in the build can depend on when they were fetched.
+const NORMALIZED_MTIME: i64 = 1;
 
 pub fn create_context_dir(context_name: &str) -> Result<PathBuf, io::Error> {
     let mut context_dir = env::temp_dir();
@@ -52,14 +57,98 @@ pub fn set_readonly_all<P: AsRef<Path>> (
     Ok(())
 }
 
+/// Undoes enough of [set_readonly_all] for `dir` to be removed. Unlike that
+/// function, this never follows symlinks, since a package may well contain
+/// symlinks that point outside of itself or nowhere at all.
+pub fn set_writable_dirs<P: AsRef<Path>>(dir: P) -> Result<(), io::Error> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let meta = fs::symlink_metadata(&dir)?;
+    if meta.is_dir() {
+        let mut perms = meta.permissions();
+        perms.set_mode(perms.mode() | 0o700);
+        fs::set_permissions(&dir, perms)?;
+        for entry in fs::read_dir(dir)? {
+            set_writable_dirs(entry?.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Sets the modification and access times of everything under `path` to the
+/// same fixed time, without following symlinks.
+pub fn normalize_mtimes(path: &Path) -> Result<(), io::Error> {
+    let meta = fs::symlink_metadata(path)?;
+    if meta.is_dir() {
+        for entry in fs::read_dir(path)? {
+            normalize_mtimes(&entry?.path())?;
+        }
+    }
+    // Directories last, since adding to them changed their times
+    let time = FileTime::from_unix_time(NORMALIZED_MTIME, 0);
+    filetime::set_symlink_file_times(path, time, time)
+}
+
+/// Copies the directory `src` to `dest`, which must not exist yet. Symlinks
+/// are copied as symlinks, and file permissions are kept. Anything that is
+/// not a file, directory or symlink, such as a FIFO or a device, is an error.
+pub fn copy_dir_all(src: &Path, dest: &Path) -> Result<(), io::Error> {
+    copy_dir_filtered(src, dest, &|_, _| false)
+}
+
+/// As [copy_dir_all], but leaves out everything for which `skip` returns
+/// true. It is given the path relative to `src`, and whether it is a
+/// directory.
+pub fn copy_dir_filtered(
+    src: &Path,
+    dest: &Path,
+    skip: &dyn Fn(&Path, bool) -> bool
+) -> Result<(), io::Error> {
+    copy_dir_inner(src, dest, Path::new(""), skip)
+}
+
+fn copy_dir_inner(
+    src: &Path,
+    dest: &Path,
+    rel: &Path,
+    skip: &dyn Fn(&Path, bool) -> bool
+) -> Result<(), io::Error> {
+    fs::create_dir(dest)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let entry_rel = rel.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if skip(&entry_rel, file_type.is_dir()) {
+            continue;
+        }
+        let target = dest.join(entry.file_name());
+        if file_type.is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(entry.path())?, &target)?;
+        } else if file_type.is_dir() {
+            copy_dir_inner(&entry.path(), &target, &entry_rel, skip)?;
+        } else if file_type.is_file() {
+            fs::copy(entry.path(), &target)?;
+        } else {
+            // Reading a FIFO would block, and a device or socket can't be
+            // copied as a file anyway
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a regular file, directory or symlink",
+                        entry.path().display())
+            ));
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
-    fn test_create_builddir() {
+    fn test_create_context_dir() {
         let mut test_path = env::temp_dir();
-        let val = create_builddir("pkgname").unwrap();
+        let val = create_context_dir("pkgname-build").unwrap();
         test_path.push("pkgname-build");
         assert_eq!(test_path, val);
         assert!(test_path.exists());
@@ -69,7 +158,7 @@ mod tests {
     #[test]
     fn test_create_outdir() {
         let mut test_path = env::temp_dir();
-        create_outdir(&mut test_path.clone(), "ident").unwrap();
+        create_outdir(test_path.clone(), "ident").unwrap();
         test_path.push("ident");
         assert!(test_path.exists());
         fs::remove_dir(test_path).unwrap();
@@ -93,4 +182,22 @@ mod tests {
         set_readonly_all(&test_path, false).unwrap();
         fs::remove_dir_all(test_path).unwrap();
     }
+
+    #[test]
+    fn test_copy_dir_special_file() {
+        use nix::sys::stat::Mode;
+
+        let tmp = env::temp_dir().join(format!("yafpm-copy-special-test-{}", std::process::id()));
+        let src = tmp.join("src");
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("file"), b"file").unwrap();
+        nix::unistd::mkfifo(&src.join("fifo"), Mode::S_IRWXU).unwrap();
+        let err = copy_dir_all(&src, &tmp.join("dest")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        // It can still be left out
+        copy_dir_filtered(&src, &tmp.join("filtered"), &|rel, _| rel == Path::new("fifo"))
+            .unwrap();
+        assert_eq!(fs::read(tmp.join("filtered/file")).unwrap(), b"file");
+        fs::remove_dir_all(tmp).unwrap();
+    }
 }
@@ -17,124 +17,292 @@
 
 use std::io;
 use std::fmt;
+use std::str::FromStr;
 use digest::Digest;
 use digest::generic_array::GenericArray;
-use data_encoding::HEXLOWER;
+use data_encoding::HEXLOWER_PERMISSIVE as HEX;
+use blake2::{Blake2b, Blake2s};
+use sha2::{Sha256, Sha512};
 
 #[derive(Debug, thiserror::Error)]
 pub enum HashError {
-    #[error("Expected hash: {} Found hash: {}",
-            HEXLOWER.encode(.expected),
-            HEXLOWER.encode(.found))]
-    BadHash{expected: Vec<u8>, found: Vec<u8>},
+    #[error("Expected hash: {expected} Found hash: {found}")]
+    BadHash{expected: ItemHash, found: ItemHash},
     #[error(transparent)]
     IOError(#[from]io::Error)
 }
 
-type InnerGA<D> = GenericArray<u8, <D as Digest>::OutputSize>;
+#[derive(Debug, thiserror::Error)]
+pub enum ParseHashError {
+    #[error("unknown hash algorithm {0}")]
+    UnknownAlgo(String),
+    #[error("a {algo} hash should be {expected} hexadecimal characters, not {found}")]
+    BadLength{algo: HashAlgo, expected: usize, found: usize},
+    #[error("hex parsing error at position {0}")]
+    BadHex(usize),
+}
+
+/// The digest algorithms that an [ItemHash] can use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HashAlgo {
+    Blake2s,
+    Blake2b,
+    Sha256,
+    Sha512,
+    Blake3,
+}
+
+impl HashAlgo {
+    /// The name used to prefix hashes of this algorithm, as in `sha256:...`.
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgo::Blake2s => "blake2s",
+            HashAlgo::Blake2b => "blake2b",
+            HashAlgo::Sha256 => "sha256",
+            HashAlgo::Sha512 => "sha512",
+            HashAlgo::Blake3 => "blake3",
+        }
+    }
+
+    /// The length of a digest in bytes.
+    pub fn output_size(self) -> usize {
+        match self {
+            HashAlgo::Blake2s => <Blake2s as Digest>::output_size(),
+            HashAlgo::Blake2b => <Blake2b as Digest>::output_size(),
+            HashAlgo::Sha256 => <Sha256 as Digest>::output_size(),
+            HashAlgo::Sha512 => <Sha512 as Digest>::output_size(),
+            HashAlgo::Blake3 => blake3::OUT_LEN,
+        }
+    }
+
+    pub fn hasher(self) -> Hasher {
+        match self {
+            HashAlgo::Blake2s => Hasher::Blake2s(Blake2s::new()),
+            HashAlgo::Blake2b => Hasher::Blake2b(Blake2b::new()),
+            HashAlgo::Sha256 => Hasher::Sha256(Sha256::new()),
+            HashAlgo::Sha512 => Hasher::Sha512(Sha512::new()),
+            HashAlgo::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+}
+
+impl fmt::Display for HashAlgo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for HashAlgo {
+    type Err = ParseHashError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "blake2s" => Ok(HashAlgo::Blake2s),
+            "blake2b" => Ok(HashAlgo::Blake2b),
+            "sha256" => Ok(HashAlgo::Sha256),
+            "sha512" => Ok(HashAlgo::Sha512),
+            "blake3" => Ok(HashAlgo::Blake3),
+            _ => Err(ParseHashError::UnknownAlgo(s.to_string())),
+        }
+    }
+}
+
+/// A running hash computation for any of the [HashAlgo]s. Data is fed in
+/// through its [io::Write] implementation.
+pub enum Hasher {
+    Blake2s(Blake2s),
+    Blake2b(Blake2b),
+    Sha256(Sha256),
+    Sha512(Sha512),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn algo(&self) -> HashAlgo {
+        match self {
+            Hasher::Blake2s(_) => HashAlgo::Blake2s,
+            Hasher::Blake2b(_) => HashAlgo::Blake2b,
+            Hasher::Sha256(_) => HashAlgo::Sha256,
+            Hasher::Sha512(_) => HashAlgo::Sha512,
+            Hasher::Blake3(_) => HashAlgo::Blake3,
+        }
+    }
+
+    pub fn finish(self) -> ItemHash {
+        let algo = self.algo();
+        let digest = match self {
+            Hasher::Blake2s(h) => h.result().to_vec(),
+            Hasher::Blake2b(h) => h.result().to_vec(),
+            Hasher::Sha256(h) => h.result().to_vec(),
+            Hasher::Sha512(h) => h.result().to_vec(),
+            Hasher::Blake3(h) => h.finalize().as_bytes().to_vec(),
+        };
+        ItemHash{algo, digest}
+    }
+}
+
+impl io::Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Hasher::Blake2s(h) => h.input(buf),
+            Hasher::Blake2b(h) => h.input(buf),
+            Hasher::Sha256(h) => h.input(buf),
+            Hasher::Sha512(h) => h.input(buf),
+            Hasher::Blake3(h) => { h.update(buf); }
+        }
+        Ok(buf.len())
+    }
 
-pub struct ItemHash<D: Digest>(InnerGA<D>);
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A digest tagged with the algorithm that produced it. In recipes it is
+/// written as `<algo>:<hex>`, e.g. `sha256:...`; a bare hex string is taken
+/// to be Blake2s, which is what yafpm used before it knew other algorithms.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ItemHash {
+    algo: HashAlgo,
+    digest: Vec<u8>,
+}
+
+impl ItemHash {
+    pub fn algo(&self) -> HashAlgo {
+        self.algo
+    }
 
-impl<D: Digest> ItemHash<D> {
     pub fn verify_hash_from_fn<T,S>(
         &self,
-        func: impl Fn(T, &mut D) -> Result<S, io::Error>,
+        func: impl Fn(T, &mut Hasher) -> Result<S, io::Error>,
         object: T
     ) -> Result<S, HashError> {
-        let mut hasher = D::new();
+        let mut hasher = self.algo.hasher();
         let ok = func(object, &mut hasher)?;
-        let hasher_result = hasher.result();
-        if hasher_result != self.0 {
+        let found = hasher.finish();
+        if found != *self {
             return Err(HashError::BadHash {
-                expected: self.0.to_vec(),
-                found: hasher_result.to_vec()
+                expected: self.clone(),
+                found
             });
         }
         Ok(ok)
     }
 }
 
-impl<D: Digest> fmt::LowerHex for ItemHash<D>
-    where InnerGA<D>: fmt::LowerHex
-{
+impl fmt::Display for ItemHash {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
-        self.0.fmt(f)
+        write!(f, "{}:{:x}", self.algo, self)
     }
 }
 
-impl<D: Digest> From<InnerGA<D>> for ItemHash<D> {
-    fn from(d: InnerGA<D>) -> Self {
-        ItemHash(d)
+impl fmt::LowerHex for ItemHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        f.write_str(&HEX.encode(&self.digest))
     }
 }
 
-impl<D: Digest> AsRef<[u8]> for ItemHash<D> {
-    fn as_ref(&self) -> &[u8] {
-        self.0.as_ref()
+impl FromStr for ItemHash {
+    type Err = ParseHashError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (algo, hex) = match s.split_once(':') {
+            Some((algo, hex)) => (algo.parse()?, hex),
+            None => (HashAlgo::Blake2s, s),
+        };
+        let expected = HEX.encode_len(algo.output_size());
+        if hex.len() != expected {
+            return Err(ParseHashError::BadLength{
+                algo,
+                expected,
+                found: hex.len()
+            });
+        }
+        let digest = HEX.decode(hex.as_bytes()).map_err(
+            |e| ParseHashError::BadHex(e.position))?;
+        Ok(ItemHash{algo, digest})
     }
 }
 
+type InnerGA<D> = GenericArray<u8, <D as Digest>::OutputSize>;
+
+impl From<InnerGA<Blake2s>> for ItemHash {
+    fn from(d: InnerGA<Blake2s>) -> Self {
+        ItemHash{algo: HashAlgo::Blake2s, digest: d.to_vec()}
+    }
+}
 
-// TODO: Find some way (enum? trait object?) to encapsulate the type argument
-// of ItemHash and make Resource, BuildCxt, etc, able to use different hash
-// algos without needing a type argument of their own.
+impl AsRef<[u8]> for ItemHash {
+    fn as_ref(&self) -> &[u8] {
+        &self.digest
+    }
+}
 
 #[cfg(feature = "serde")]
 mod serde_impl{
     use super::*;
     use serde::{ser,de};
-    use data_encoding::HEXLOWER_PERMISSIVE as HEX;
 
-    impl<D: Digest> ser::Serialize for ItemHash<D> where 
-        InnerGA<D>: fmt::LowerHex
-    {
+    impl ser::Serialize for ItemHash {
         fn serialize<S: ser::Serializer>(
             &self,
             serializer: S
         ) -> Result<S::Ok, S::Error> {
-            let output = format!("{:x}", self.0);
-            serializer.serialize_str(&output)
+            serializer.collect_str(self)
         }
     }
 
-    struct ItemHashVisitor<H: Digest>(std::marker::PhantomData<H>);
-    
-    impl<H: Digest> ItemHashVisitor<H> {
-        fn new() -> Self {ItemHashVisitor(std::marker::PhantomData)}
-    }
+    struct ItemHashVisitor;
 
-    impl<'de, H: Digest> de::Visitor<'de> for ItemHashVisitor<H> {
-        type Value = ItemHash<H>;
+    impl<'de> de::Visitor<'de> for ItemHashVisitor {
+        type Value = ItemHash;
 
         fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
-            let expected_len = HEX.encode_len(<H as Digest>::output_size());
-            write!(f, "a {} char hexadecimal string", expected_len)
+            f.write_str("a hexadecimal string, optionally prefixed by a hash algorithm")
         }
 
-        fn visit_borrowed_str<E: de::Error> (
+        fn visit_str<E: de::Error> (
             self,
-            v:&'de str
+            v: &str
         ) -> Result<Self::Value, E> {
-            let mut arr = InnerGA::<H>::default();
-            let expected_len = HEX.encode_len(<H as Digest>::output_size());
-            let found_len = v.len();
-            if found_len != expected_len {
-                return Err(E::invalid_length(found_len, &self));
-            }
-            // On its own, this can panic, but we should have ruled out the
-            // possibility above
-            HEX.decode_mut(v.as_bytes(), &mut arr).map_err(
-                |e| E::custom(format!("hex parsing error, {}", e.error)))?;
-
-            Ok(ItemHash(arr))
+            v.parse().map_err(E::custom)
         }
     }
 
-    impl<'de, 'a, H: Digest> de::Deserialize<'de> for ItemHash<H> {
+    impl<'de> de::Deserialize<'de> for ItemHash {
         fn deserialize<D: de::Deserializer<'de>>(
             deserializer: D
         ) -> Result<Self, D::Error> {
-            deserializer.deserialize_str(ItemHashVisitor::<H>::new())
+            deserializer.deserialize_str(ItemHashVisitor)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_parse_item_hash() {
+        let hex = "26f175461396f1cb925805416d6eb75dc867357764457ccb9a8488b0a6e86bc6";
+        let bare: ItemHash = hex.parse().unwrap();
+        let tagged: ItemHash = format!("blake2s:{}", hex).parse().unwrap();
+        assert_eq!(bare, tagged);
+        assert_eq!(tagged.to_string(), format!("blake2s:{}", hex));
+        let sha: ItemHash = format!("sha256:{}", hex).parse().unwrap();
+        assert_eq!(sha.algo(), HashAlgo::Sha256);
+        assert!(format!("sha512:{}", hex).parse::<ItemHash>().is_err());
+        assert!(format!("md5:{}", hex).parse::<ItemHash>().is_err());
+    }
+
+    #[test]
+    fn test_verify_hash_dispatch() {
+        let sha256: ItemHash =
+"sha256:b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+            .parse().unwrap();
+        let write = |data: &[u8], h: &mut Hasher| h.write_all(data);
+        assert!(sha256.verify_hash_from_fn(write, b"hello world").is_ok());
+        assert!(sha256.verify_hash_from_fn(write, b"goodbye world").is_err());
+    }
+}
@@ -0,0 +1,215 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+// 
+// Copyright (C) 2021 John Arnold
+//
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! The init that runs as PID 1 in the PID namespace of a build.
+//!
+//! The first process forked after [setup_new_namespace] becomes PID 1 of the
+//! new namespace, and so inherits every process orphaned in it. Rather than
+//! exec the build command itself, it forks once more and stays behind to reap
+//! orphans, pass on signals to the build command, and kill whatever is left
+//! once the build command exits, so that nothing keeps the build's mounts
+//! busy. As PID 1 can't die of a signal sent from inside its namespace, it
+//! reports how the build command exited through a [StatusPipe].
+//!
+//! [setup_new_namespace]: crate::namespace::setup_new_namespace
+
+use std::os::unix::io::RawFd;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::sys::signal::{kill, SigSet, SigmaskHow, Signal, sigprocmask};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{close, fork, pipe2, read, write, ForkResult, Pid};
+
+/// The signals that init passes on to the build command.
+const FORWARDED_SIGNALS: &[Signal] = &[
+    Signal::SIGHUP,
+    Signal::SIGINT,
+    Signal::SIGQUIT,
+    Signal::SIGTERM,
+    Signal::SIGUSR1,
+    Signal::SIGUSR2,
+];
+
+/// The pipe through which init reports the wait status of the build command.
+pub struct StatusPipe {
+    read: RawFd,
+    write: Option<RawFd>,
+}
+
+impl StatusPipe {
+    pub fn new() -> Result<Self, nix::Error> {
+        let (read, write) = pipe2(OFlag::O_CLOEXEC)?;
+        Ok(StatusPipe { read, write: Some(write) })
+    }
+
+    /// The end of the pipe to hand to [become_init].
+    pub fn writer(&self) -> RawFd {
+        // Unwrap is fine, it's only taken by read_status, which consumes self
+        self.write.unwrap()
+    }
+
+    /// Reads the status reported by init, once init has exited. Returns
+    /// `None` if init never got as far as reporting one.
+    pub fn read_status(mut self) -> Option<ExitStatus> {
+        // Otherwise the read would wait on our own end of the pipe
+        if let Some(fd) = self.write.take() {
+            let _ = close(fd);
+        }
+        let mut buf = [0; 4];
+        let mut len = 0;
+        while len < buf.len() {
+            match read(self.read, &mut buf[len..]) {
+                Ok(0) => return None,
+                Ok(n) => len += n,
+                Err(nix::Error::Sys(Errno::EINTR)) => continue,
+                Err(_) => return None,
+            }
+        }
+        Some(ExitStatus::from_raw(i32::from_ne_bytes(buf)))
+    }
+}
+
+impl Drop for StatusPipe {
+    fn drop(&mut self) {
+        let _ = close(self.read);
+        if let Some(fd) = self.write {
+            let _ = close(fd);
+        }
+    }
+}
+
+fn forwarded_set() -> SigSet {
+    let mut set = SigSet::empty();
+    for &sig in FORWARDED_SIGNALS {
+        set.add(sig);
+    }
+    set.add(Signal::SIGCHLD);
+    set
+}
+
+// Encodes a wait status the way wait(2) does, which is what ExitStatus
+// expects.
+fn raw_status(status: WaitStatus) -> Option<i32> {
+    match status {
+        WaitStatus::Exited(_, code) => Some((code & 0xff) << 8),
+        WaitStatus::Signaled(_, sig, core) => {
+            Some(sig as i32 | if core { 0x80 } else { 0 })
+        }
+        _ => None,
+    }
+}
+
+/// Forks the process that goes on to exec the build command, which is the
+/// only one this returns in. The calling process becomes init, and exits
+/// once the build command has exited and every other process in the
+/// namespace is gone, after writing the build command's wait status to
+/// `status_fd`.
+///
+/// This is meant to be called from `pre_exec`, in the first process of a new
+/// PID namespace. Like the rest of `pre_exec`, it must not allocate.
+pub fn become_init(status_fd: RawFd) -> Result<(), nix::Error> {
+    let signals = forwarded_set();
+    // Blocked before forking, so that none arrive before init waits for them
+    sigprocmask(SigmaskHow::SIG_BLOCK, Some(&signals), None)?;
+    let main = match unsafe { fork() }? {
+        ForkResult::Child => {
+            sigprocmask(SigmaskHow::SIG_UNBLOCK, Some(&signals), None)?;
+            return Ok(());
+        }
+        ForkResult::Parent { child } => child,
+    };
+    close_other_fds(status_fd);
+    let status = supervise(main, &signals);
+    let code = match status {
+        Some(raw) => {
+            let _ = write(status_fd, &raw.to_ne_bytes());
+            0
+        }
+        None => 1,
+    };
+    unsafe { nix::libc::_exit(code) }
+}
+
+// Init holds on to whatever the process it was forked from had open, which
+// includes the pipe through which Command learns that exec succeeded, so
+// that spawning would otherwise wait for the whole build.
+fn close_other_fds(keep: RawFd) {
+    let max = unsafe { nix::libc::sysconf(nix::libc::_SC_OPEN_MAX) };
+    let max = if max < 0 { 1024 } else { max.min(65536) as RawFd };
+    for fd in 3..max {
+        if fd != keep {
+            let _ = close(fd);
+        }
+    }
+}
+
+// Runs init until the build command has exited and been cleaned up after,
+// returning its raw wait status.
+fn supervise(main: Pid, signals: &SigSet) -> Option<i32> {
+    loop {
+        match signals.wait() {
+            Ok(Signal::SIGCHLD) => (),
+            Ok(sig) => {
+                let _ = kill(main, sig);
+                continue;
+            }
+            Err(_) => continue,
+        }
+        if let Some(status) = reap(main) {
+            // Everything but init itself
+            let _ = kill(Pid::from_raw(-1), Signal::SIGKILL);
+            while let Err(nix::Error::Sys(Errno::EINTR)) | Ok(_)
+                = waitpid(None, None) {}
+            return raw_status(status);
+        }
+    }
+}
+
+// Reaps every child that has exited, returning the status of `main` if it
+// was among them.
+fn reap(main: Pid) -> Option<WaitStatus> {
+    let mut main_status = None;
+    loop {
+        match waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::StillAlive) | Err(_) => return main_status,
+            Ok(status) if status.pid() == Some(main) => {
+                if raw_status(status).is_some() {
+                    main_status = Some(status);
+                }
+            }
+            Ok(_) => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_raw_status() {
+        let pid = Pid::from_raw(2);
+        let exited = raw_status(WaitStatus::Exited(pid, 3)).unwrap();
+        assert_eq!(ExitStatus::from_raw(exited).code(), Some(3));
+        let signaled = WaitStatus::Signaled(pid, Signal::SIGSEGV, false);
+        let signaled = ExitStatus::from_raw(raw_status(signaled).unwrap());
+        assert_eq!(signaled.signal(), Some(Signal::SIGSEGV as i32));
+        assert_eq!(raw_status(WaitStatus::StillAlive), None);
+    }
+}
@@ -22,14 +22,35 @@
 
 mod context;
 mod namespace;
+mod init;
+mod limits;
+mod cgroup;
 mod walk_dir;
+mod refs;
+mod dir_diff;
 mod resource;
 mod dirs;
 mod hashes;
 mod package;
+mod store;
+mod unpack;
+mod patch;
 
-pub use context::{BuildCxt, BuildError, ShellCxt, ShellError};
-pub use resource::Resource;
+pub use context::{BuildCxt, BuildError, BuildReport, CheckReport, OutputCheck};
+pub use context::{ShellCxt, ShellError};
+pub use context::{build_order, BuildGraphError, TeardownError};
+pub use resource::{FetchOpts, ProgressFn, Resource, ResourceError};
+pub use resource::{CACHE_DIR, MIRRORS_FILE};
+pub use unpack::{ArchiveFormat, UnpackError};
+pub use patch::{Patch, PatchError};
+pub use namespace::{Sandbox, ALLOWED_DEVICES};
+pub use limits::{BuildLimits, ResourceUsage};
+pub use cgroup::CgroupError;
 #[cfg(feature = "serde")]
 pub use resource::url_serde::SERDE_BASE_URL;
-pub use package::Package;
+pub use package::{Output, Package, MAIN_OUTPUT};
+pub use hashes::{HashAlgo, HashError, ItemHash, ParseHashError};
+pub use dir_diff::FileDiff;
+pub use walk_dir::{calculate_directory_hash, write_archive, DirHashVersion};
+pub use store::{collect_garbage, StoreError, GC_ROOTS_DIR};
+pub use store::{read_record, read_all_records, reverse_deps, PkgRecord};
@@ -0,0 +1,280 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+// 
+// Copyright (C) 2021 John Arnold
+//
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! Limits on what a build may use, and accounting for what it did use.
+//!
+//! The limits are applied to the build command as rlimits, and also through
+//! a cgroup when a cgroup v2 subtree has been delegated to yafpm (see
+//! [Cgroup](crate::cgroup::Cgroup)), since rlimits only hold for each process
+//! on its own.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use nix::libc;
+use nix::sched::{sched_getaffinity, sched_setaffinity, CpuSet};
+use nix::unistd::Pid;
+
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
+
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
+/// Limits on what a build may use. None of them are set by default.
+pub struct BuildLimits {
+    /// How many seconds the build command may run for.
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    timeout: Option<u64>,
+    /// How many seconds the build command may go without printing anything.
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    silence_timeout: Option<u64>,
+    /// How many bytes of memory the build may use. Without a cgroup, this
+    /// only limits the data segment of each process.
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    memory: Option<u64>,
+    /// How many CPUs the build may run on.
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    cpus: Option<u32>,
+    /// How many files each process of the build may have open.
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    open_files: Option<u64>,
+    /// How many processes the build may run at once.
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    processes: Option<u64>,
+    /// How many bytes the output of the build may take up on disk. This is
+    /// also the largest a single file written by the build may be.
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    disk: Option<u64>,
+}
+
+impl BuildLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_timeout(&mut self, secs: u64) -> &mut Self {
+        self.timeout = Some(secs);
+        self
+    }
+
+    pub fn set_silence_timeout(&mut self, secs: u64) -> &mut Self {
+        self.silence_timeout = Some(secs);
+        self
+    }
+
+    pub fn set_memory(&mut self, bytes: u64) -> &mut Self {
+        self.memory = Some(bytes);
+        self
+    }
+
+    pub fn set_cpus(&mut self, cpus: u32) -> &mut Self {
+        self.cpus = Some(cpus);
+        self
+    }
+
+    pub fn set_open_files(&mut self, files: u64) -> &mut Self {
+        self.open_files = Some(files);
+        self
+    }
+
+    pub fn set_processes(&mut self, processes: u64) -> &mut Self {
+        self.processes = Some(processes);
+        self
+    }
+
+    pub fn set_disk(&mut self, bytes: u64) -> &mut Self {
+        self.disk = Some(bytes);
+        self
+    }
+
+    /// Replaces the limits here with those that are set in `other`.
+    pub fn merge(&mut self, other: &BuildLimits) -> &mut Self {
+        self.timeout = other.timeout.or(self.timeout);
+        self.silence_timeout = other.silence_timeout.or(self.silence_timeout);
+        self.memory = other.memory.or(self.memory);
+        self.cpus = other.cpus.or(self.cpus);
+        self.open_files = other.open_files.or(self.open_files);
+        self.processes = other.processes.or(self.processes);
+        self.disk = other.disk.or(self.disk);
+        self
+    }
+
+    pub(crate) fn timeout(&self) -> Option<Duration> {
+        self.timeout.map(Duration::from_secs)
+    }
+
+    pub(crate) fn silence_timeout(&self) -> Option<Duration> {
+        self.silence_timeout.map(Duration::from_secs)
+    }
+
+    pub(crate) fn memory(&self) -> Option<u64> {
+        self.memory
+    }
+
+    pub(crate) fn cpus(&self) -> Option<u32> {
+        self.cpus
+    }
+
+    pub(crate) fn processes(&self) -> Option<u64> {
+        self.processes
+    }
+
+    pub(crate) fn disk(&self) -> Option<u64> {
+        self.disk
+    }
+
+    /// Sets the rlimits and CPU affinity of the calling process. This is
+    /// meant to be called from `pre_exec`, so it must not allocate.
+    pub(crate) fn apply(&self) -> Result<(), nix::Error> {
+        set_rlimit(libc::RLIMIT_DATA, self.memory)?;
+        set_rlimit(libc::RLIMIT_NOFILE, self.open_files)?;
+        set_rlimit(libc::RLIMIT_NPROC, self.processes)?;
+        set_rlimit(libc::RLIMIT_FSIZE, self.disk)?;
+        if let Some(cpus) = self.cpus {
+            // The first `cpus` of the ones we may already run on
+            let allowed = sched_getaffinity(Pid::from_raw(0))?;
+            let mut set = CpuSet::new();
+            let mut left = cpus;
+            for cpu in 0..CpuSet::count() {
+                if left == 0 {
+                    break;
+                }
+                if allowed.is_set(cpu)? {
+                    set.set(cpu)?;
+                    left -= 1;
+                }
+            }
+            sched_setaffinity(Pid::from_raw(0), &set)?;
+        }
+        Ok(())
+    }
+}
+
+// The type of the resource argument of setrlimit, which glibc gives an enum
+// of its own and other libcs leave as an int
+#[cfg(target_env = "gnu")]
+type RlimitResource = libc::__rlimit_resource_t;
+#[cfg(not(target_env = "gnu"))]
+type RlimitResource = libc::c_int;
+
+// Lowers both the soft and hard limit of `resource`, if a limit is given.
+fn set_rlimit(resource: RlimitResource, limit: Option<u64>)
+    -> Result<(), nix::Error>
+{
+    let limit = match limit {
+        Some(l) => l as libc::rlim_t,
+        None => return Ok(()),
+    };
+    let rlim = libc::rlimit { rlim_cur: limit, rlim_max: limit };
+    if unsafe { libc::setrlimit(resource, &rlim) } == 0 {
+        Ok(())
+    } else {
+        Err(nix::Error::last())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// What a build used, as far as it could be measured.
+pub struct ResourceUsage {
+    /// How long the build command ran for.
+    pub wall_time: Duration,
+    /// CPU time spent by the build in user mode, summed over its processes.
+    pub user_time: Duration,
+    /// CPU time spent by the build in the kernel, summed over its processes.
+    pub system_time: Duration,
+    /// The largest resident set of any one process of the build, in bytes.
+    pub max_rss: u64,
+    /// The most memory the build used at once, in bytes. This is only known
+    /// when the build ran in a cgroup.
+    pub peak_memory: Option<u64>,
+    /// How many bytes the output of the build takes up on disk.
+    pub output_size: u64,
+}
+
+impl fmt::Display for ResourceUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.1}s wall, {:.1}s user, {:.1}s system, {} KiB max RSS",
+               self.wall_time.as_secs_f64(),
+               self.user_time.as_secs_f64(),
+               self.system_time.as_secs_f64(),
+               self.max_rss / 1024)?;
+        if let Some(peak) = self.peak_memory {
+            write!(f, ", {} KiB peak memory", peak / 1024)?;
+        }
+        write!(f, ", {} KiB output", self.output_size / 1024)
+    }
+}
+
+fn children_rusage() -> libc::rusage {
+    // Zeroed is a valid rusage, and getrusage can't fail for RUSAGE_CHILDREN
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    unsafe { libc::getrusage(libc::RUSAGE_CHILDREN, &mut usage) };
+    usage
+}
+
+fn timeval(tv: libc::timeval) -> Duration {
+    Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000)
+}
+
+/// Measures the resources used by the children of this process that are
+/// waited for between [UsageMeter::start] and [UsageMeter::finish]. The init
+/// of a build waits for every process in the build, so their usage adds up
+/// to that of init.
+pub(crate) struct UsageMeter {
+    start: Instant,
+    rusage: libc::rusage,
+}
+
+impl UsageMeter {
+    pub(crate) fn start() -> Self {
+        UsageMeter { start: Instant::now(), rusage: children_rusage() }
+    }
+
+    pub(crate) fn finish(self) -> ResourceUsage {
+        let end = children_rusage();
+        ResourceUsage {
+            wall_time: self.start.elapsed(),
+            user_time: timeval(end.ru_utime)
+                .saturating_sub(timeval(self.rusage.ru_utime)),
+            system_time: timeval(end.ru_stime)
+                .saturating_sub(timeval(self.rusage.ru_stime)),
+            // In KiB. This is a maximum over every child there has been, so
+            // it can't be narrowed down to this build.
+            max_rss: end.ru_maxrss as u64 * 1024,
+            peak_memory: None,
+            output_size: 0,
+        }
+    }
+}
+
+/// How many bytes the files in `dir` take up on disk.
+pub(crate) fn disk_usage(dir: &Path) -> Result<u64, io::Error> {
+    use std::os::unix::fs::MetadataExt;
+    let mut total = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        total += meta.blocks() * 512;
+        if meta.is_dir() {
+            total += disk_usage(&entry.path())?;
+        }
+    }
+    Ok(total)
+}
@@ -16,16 +16,32 @@
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
 use std::io;
+use std::mem;
+use std::ptr;
+use std::ffi::CString;
 use std::fs::File;
 use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
+use nix::errno::Errno;
+use nix::libc;
+use nix::NixPath;
 use nix::sched::{unshare, CloneFlags};
-use nix::unistd::geteuid;
+use nix::unistd::{getegid, geteuid};
 use nix::mount::{mount,umount,MsFlags};
 
 use crate::package::Package as PKG;
 
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
+
+/// The device nodes that a [Sandbox] may bind mount from the host.
+pub const ALLOWED_DEVICES: &[&str] =
+    &["null", "zero", "full", "random", "urandom", "tty"];
+const DEFAULT_DEVICES: &[&str] = &["null", "zero", "full", "random", "urandom"];
+
 #[derive(Debug, thiserror::Error)]
+#[allow(clippy::enum_variant_names)]
 pub enum NSError {
     #[error("Unable to create new namespace")]
     NewError(#[source] nix::Error),
@@ -42,8 +58,71 @@ pub enum NSError {
         #[source]
         err: nix::Error
     },
+    #[error("Error while making {} read-only", .0.display())]
+    ReadOnlyError(PathBuf, #[source] nix::Error),
     #[error("Error while unmounting {}", .0.display())]
-    BindUMountError(PathBuf, #[source] nix::Error)
+    BindUMountError(PathBuf, #[source] nix::Error),
+    #[error("Device {0} is not one that a build may use")]
+    DeviceError(String),
+    #[error("Error while mounting {fs} on {}", .target_dir.display())]
+    MountError{
+        fs: &'static str,
+        target_dir: PathBuf,
+        #[source]
+        err: nix::Error
+    },
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
+/// The parts of a usual system that are set up in the root directory of a
+/// build, beyond its resources and dependencies. By default all of them are.
+pub struct Sandbox<'a> {
+    /// Whether to mount a procfs for the build's PID namespace on `/proc`.
+    proc: bool,
+    /// Which of [ALLOWED_DEVICES] to bind mount into `/dev`.
+    #[cfg_attr(feature = "serde", serde(borrow))]
+    devices: Vec<&'a str>,
+    /// Whether to mount an empty tmpfs on `/tmp`.
+    tmp: bool,
+}
+
+impl Default for Sandbox<'_> {
+    fn default() -> Self {
+        Sandbox {
+            proc: true,
+            devices: DEFAULT_DEVICES.to_vec(),
+            tmp: true,
+        }
+    }
+}
+
+impl<'a> Sandbox<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_proc(&mut self, proc: bool) -> &mut Self {
+        self.proc = proc;
+        self
+    }
+
+    pub fn set_devices<I>(&mut self, iter: I) -> &mut Self
+        where I: IntoIterator<Item = &'a str>
+    {
+        self.devices = iter.into_iter().collect();
+        self
+    }
+
+    pub fn set_tmp(&mut self, tmp: bool) -> &mut Self {
+        self.tmp = tmp;
+        self
+    }
+
+    pub(crate) fn proc(&self) -> bool {
+        self.proc
+    }
 }
 
 fn get_uid_map() -> String {
@@ -51,25 +130,80 @@ fn get_uid_map() -> String {
     format!("0 {} 1\n", euid)
 }
 
+fn get_gid_map() -> String {
+    let egid = getegid();
+    format!("0 {} 1\n", egid)
+}
+
+fn write_proc_file(path: &str, contents: &str) -> Result<(), NSError> {
+    let mut file = File::create(path).map_err(NSError::UMapError)?;
+    file.write_all(contents.as_bytes()).map_err(NSError::UMapError)
+}
+
 pub fn setup_new_namespace() -> Result<(), NSError> {
     let uid_map = get_uid_map();
     let flags = CloneFlags::CLONE_NEWUSER | CloneFlags::CLONE_NEWNS
         | CloneFlags::CLONE_NEWNET | CloneFlags::CLONE_NEWPID;
-    unshare(flags).map_err(|e| NSError::NewError(e))?;
-    let mut file = File::create("/proc/self/uid_map").map_err(
-        |e| NSError::UMapError(e))?;
-    file.write_all(uid_map.as_bytes()).map_err(
-        |e| NSError::UMapError(e))?;
+    let gid_map = get_gid_map();
+    unshare(flags).map_err(NSError::NewError)?;
+    write_proc_file("/proc/self/uid_map", &uid_map)?;
+    // Without a group mapping nothing can be created on filesystems mounted
+    // inside the namespace, such as the tmpfs of a Sandbox. Unprivileged
+    // processes have to give up setgroups before they can write one.
+    write_proc_file("/proc/self/setgroups", "deny")?;
+    write_proc_file("/proc/self/gid_map", &gid_map)?;
     Ok(())
 }
 
+// The flags of the mount that `path` is on which a read-only remount of a bind
+// mount of `path` has to keep. Those set on the mount we were given are locked
+// in a user namespace, and remounting without them fails with EPERM.
+fn locked_flags(path: &Path) -> Result<MsFlags, nix::Error> {
+    // nix drops ST_RELATIME on musl, and libc doesn't define it there, so
+    // this asks libc directly and uses the kernel's value
+    const ST_RELATIME: libc::c_ulong = 0x1000;
+    let mut stat = mem::MaybeUninit::<libc::statvfs>::uninit();
+    let res = path.with_nix_path(
+        |p| unsafe { libc::statvfs(p.as_ptr(), stat.as_mut_ptr()) })?;
+    Errno::result(res)?;
+    let fs_flags = unsafe { stat.assume_init() }.f_flag;
+    let mut flags = MsFlags::empty();
+    for &(fs_flag, ms_flag) in &[
+        (libc::ST_NOSUID, MsFlags::MS_NOSUID),
+        (libc::ST_NODEV, MsFlags::MS_NODEV),
+        (libc::ST_NOEXEC, MsFlags::MS_NOEXEC),
+        (libc::ST_NOATIME, MsFlags::MS_NOATIME),
+        (libc::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+        (ST_RELATIME, MsFlags::MS_RELATIME),
+    ] {
+        if fs_flags & fs_flag != 0 {
+            flags |= ms_flag;
+        }
+    }
+    Ok(flags)
+}
+
+fn remount_readonly(bind_dir: &Path) -> Result<(), NSError> {
+    let flags = locked_flags(bind_dir).map_err(
+        |e| NSError::ReadOnlyError(bind_dir.into(), e))?
+        | MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY;
+    mount(None::<&str>, bind_dir, None::<&str>, flags, None::<&str>).map_err(
+        |e| NSError::ReadOnlyError(bind_dir.into(), e))
+}
+
+/// Bind mounts the store directories of `deps` into `build_dir`, at the
+/// same paths they have outside it. The mounts are read-only, since the build
+/// runs as root in its user namespace and so ignores file permissions.
+///
+/// Like the other functions here that mount something, this adds each mount
+/// to `mounts` as soon as it is made, for [umount_all].
 pub fn mount_dep_dirs<'a, P: AsRef<Path>>(
     pkg_store_dir: P,
     build_dir: &Path,
     deps: impl IntoIterator<Item = &'a PKG<'a>>,
+    mounts: &mut Vec<PathBuf>,
 ) -> Result<(), NSError> {
     let flags = MsFlags::MS_BIND;
-    //let ro_flags = MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY;
 
     let mut bind_dir = build_dir.to_path_buf();
     let mut dep_dir = pkg_store_dir.as_ref().to_path_buf();
@@ -86,22 +220,152 @@ pub fn mount_dep_dirs<'a, P: AsRef<Path>>(
                 target_dir: bind_dir.clone(),
                 err: e
         })?;
-        //mount(None::<&str>, &bind_dir, None::<&str>, ro_flags, None::<&str>)?;
+        mounts.push(bind_dir.clone());
+        remount_readonly(&bind_dir)?;
         bind_dir.push(build_dir); // resets bind_dir to build dir
         dep_dir.pop(); // strips dependency package identifier
     }
     Ok(())
 }
 
+fn mkdir(dir: &Path) -> Result<(), NSError> {
+    std::fs::create_dir_all(dir).map_err(
+        |e| NSError::MkDirError(dir.to_path_buf(), e))
+}
+
+/// Sets up everything that `sandbox` asks for in `build_dir`, except for
+/// `/proc`, which is left to [NewRoot::enter]. This has to come before
+/// [mount_dep_dirs], since the store may well be in `/tmp`.
+pub fn mount_sandbox(
+    build_dir: &Path,
+    sandbox: &Sandbox,
+    mounts: &mut Vec<PathBuf>,
+) -> Result<(), NSError> {
+    if sandbox.tmp {
+        let tmp_dir = build_dir.join("tmp");
+        mkdir(&tmp_dir)?;
+        let flags = MsFlags::MS_NOSUID | MsFlags::MS_NODEV;
+        mount(Some("tmpfs"), &tmp_dir, Some("tmpfs"), flags, Some("mode=1777"))
+            .map_err(|e| NSError::MountError{
+                fs: "tmpfs",
+                target_dir: tmp_dir.clone(),
+                err: e
+            })?;
+        mounts.push(tmp_dir);
+    }
+    if sandbox.proc {
+        mkdir(&build_dir.join("proc"))?;
+    }
+    if sandbox.devices.is_empty() {
+        return Ok(());
+    }
+    let dev_dir = build_dir.join("dev");
+    mkdir(&dev_dir)?;
+    for &dev in &sandbox.devices {
+        if !ALLOWED_DEVICES.contains(&dev) {
+            return Err(NSError::DeviceError(dev.to_string()));
+        }
+        let source_dir = Path::new("/dev").join(dev);
+        let target_dir = dev_dir.join(dev);
+        File::create(&target_dir).map_err(
+            |e| NSError::MkDirError(target_dir.clone(), e))?;
+        mount(Some(&source_dir), &target_dir, None::<&str>, MsFlags::MS_BIND,
+              None::<&str>).map_err(
+            |e| NSError::BindMountError{
+                source_dir,
+                target_dir: target_dir.clone(),
+                err: e
+            })?;
+        mounts.push(target_dir);
+    }
+    Ok(())
+}
+
+/// The paths that [NewRoot::enter] needs, turned into C strings ahead of
+/// time, since nothing may be allocated between forking and exec.
+pub struct NewRoot {
+    root: CString,
+    // Where to mount a procfs, if the build gets one
+    proc_dir: Option<CString>,
+}
+
+impl NewRoot {
+    /// Prepares to make `new_root` the root directory, mounting a procfs on
+    /// `/proc` first if `proc` is set.
+    pub fn new(new_root: &Path, proc: bool) -> Result<Self, io::Error> {
+        let c_path = |path: &Path| CString::new(path.as_os_str().as_bytes());
+        Ok(NewRoot {
+            root: c_path(new_root)?,
+            proc_dir: if proc { Some(c_path(&new_root.join("proc"))?) } else { None },
+        })
+    }
+
+    /// Makes the prepared root the root directory of the calling process,
+    /// with the rest of the host's mount tree detached. This is meant to be
+    /// called by the build process just before it execs the build command,
+    /// so it calls libc directly rather than through nix, which would copy
+    /// the paths.
+    ///
+    /// The process gets a mount namespace of its own first, since
+    /// `pivot_root` changes the root of every process in the namespace, and
+    /// the process that tears the build down afterwards has to keep seeing
+    /// the host.
+    ///
+    /// A procfs belongs to the PID namespace of the process that mounts it,
+    /// and only the children of the process that called
+    /// [setup_new_namespace] are in the new one, so the build process has
+    /// to mount its `/proc` itself, here.
+    pub fn enter(&self) -> Result<(), nix::Error> {
+        const SLASH: &[u8] = b"/\0";
+        const DOT: &[u8] = b".\0";
+        const PROC: &[u8] = b"proc\0";
+        let c_str = |bytes: &'static [u8]| bytes.as_ptr() as *const libc::c_char;
+        let root = self.root.as_ptr();
+        let null = ptr::null();
+
+        unshare(CloneFlags::CLONE_NEWNS)?;
+        let private = MsFlags::MS_REC | MsFlags::MS_PRIVATE;
+        Errno::result(unsafe {
+            libc::mount(null, c_str(SLASH), null, private.bits(), ptr::null())
+        })?;
+        // pivot_root needs the new root to be a mount point. The bind has to
+        // be recursive to bring along the mounts of dependencies and the
+        // sandbox.
+        let rbind = MsFlags::MS_BIND | MsFlags::MS_REC;
+        Errno::result(unsafe {
+            libc::mount(root, root, null, rbind.bits(), ptr::null())
+        })?;
+        if let Some(proc_dir) = &self.proc_dir {
+            let flags = MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC;
+            Errno::result(unsafe {
+                libc::mount(c_str(PROC), proc_dir.as_ptr(), c_str(PROC), flags.bits(),
+                            ptr::null())
+            })?;
+        }
+        Errno::result(unsafe { libc::chdir(root) })?;
+        // This stacks the old root on top of the new one, from where it can
+        // be detached without needing a directory to put it in.
+        Errno::result(unsafe {
+            libc::syscall(libc::SYS_pivot_root, c_str(DOT), c_str(DOT))
+        })?;
+        Errno::result(unsafe { libc::umount2(c_str(DOT), libc::MNT_DETACH) })?;
+        Errno::result(unsafe { libc::chdir(c_str(SLASH)) }).map(drop)
+    }
+}
+
+/// Bind mounts `out_dir` into `build_dir`, where the build sees it as
+/// `seen_as`. This is usually `out_dir` itself.
 pub fn mount_out_dir(
     build_dir: &Path,
     out_dir: &Path,
+    seen_as: &Path,
+    mounts: &mut Vec<PathBuf>,
 ) -> Result<(), NSError> {
     let flags = MsFlags::MS_BIND;
     let mut bind_dir = build_dir.to_path_buf();
 
     // This should be safe because of logic in build_cxt::exec_build
-    bind_dir.push(out_dir.strip_prefix("/").unwrap());
+    bind_dir.push(seen_as.strip_prefix("/").unwrap());
     std::fs::create_dir_all(&bind_dir).map_err(
         |e| NSError::MkDirError(bind_dir.clone(),e)
     )?;
@@ -111,43 +375,22 @@ pub fn mount_out_dir(
             target_dir: bind_dir.clone(),
             err: e
     })?;
+    mounts.push(bind_dir);
     Ok(())
 }
 
-pub fn umount_dep_dirs<'a> (
-    pkg_store_dir: &Path,
-    build_dir: &Path,
-    deps: impl IntoIterator<Item=&'a PKG<'a>>
-) -> Result<(), NSError> {
-    let mut bind_dir = build_dir.to_path_buf();
-    let mut dep_dir = pkg_store_dir.to_path_buf();
-    for dep in deps {
-        dep_dir.push(dep.pkg_ident());
-        // This should be safe because of logic in build_cxt::exec_build
-        bind_dir.push(dep_dir.strip_prefix("/").unwrap());
-        umount(&bind_dir).map_err(
-            |e| NSError::BindUMountError(bind_dir.clone(),e)
+/// Unmounts `mounts`, in the reverse of the order they were mounted in,
+/// taking each one off the list once it is unmounted.
+pub fn umount_all(mounts: &mut Vec<PathBuf>) -> Result<(), NSError> {
+    while let Some(target) = mounts.last() {
+        umount(target).map_err(
+            |e| NSError::BindUMountError(target.clone(), e)
         )?;
-        bind_dir.push(build_dir); // resets bind_dir to build dir
-        dep_dir.pop(); // strips dependency package identifier
+        mounts.pop();
     }
     Ok(())
 }
 
-pub fn umount_out_dir(
-    build_dir: &Path,
-    out_dir: &Path,
-) -> Result<(), NSError> {
-    let mut bind_dir = build_dir.to_path_buf();
-    // This should be safe because of logic in build_cxt::exec_build
-    bind_dir.push(out_dir.strip_prefix("/").unwrap());
-    umount(&bind_dir).map_err(
-        |e| NSError::BindUMountError(bind_dir.clone(),e)
-    )?;
-    Ok(())
-}
-
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -157,4 +400,10 @@ mod tests {
         let map = get_uid_map();
         assert!(map.len() > 4);
     }
+
+    #[test]
+    fn test_get_gid_map() {
+        let map = get_gid_map();
+        assert!(map.starts_with("0 "));
+    }
 }
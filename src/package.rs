@@ -17,14 +17,36 @@
 
 use std::path::PathBuf;
 use std::collections::HashMap;
-use blake2::Blake2s;
+use url::Url;
 use data_encoding::BASE32_NOPAD;
 
 use crate::hashes;
+use crate::walk_dir::DirHashVersion;
+#[cfg(feature = "serde")]
+use crate::resource::url_serde;
 
 #[cfg(feature = "serde")]
 use serde::{Serialize, Deserialize};
 
+/// The name of the output that every package has, which is installed under
+/// its `pkg_ident`.
+pub const MAIN_OUTPUT: &str = "out";
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
+/// An output of a package besides its main one, such as its headers or its
+/// documentation, which is installed to a store path of its own.
+pub struct Output<'a> {
+    pub name: &'a str,
+    pub(crate) hash: hashes::ItemHash,
+}
+
+impl<'a> Output<'a> {
+    pub fn new(name: &'a str, hash: hashes::ItemHash) -> Self {
+        Output { name, hash }
+    }
+}
+
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 /// A package that one might install to a system.
 pub struct Package<'a> {
@@ -33,8 +55,23 @@ pub struct Package<'a> {
     pub pkg_name: &'a str,
     #[cfg_attr(feature = "serde", serde(rename = "package_version"))]
     #[cfg_attr(feature = "serde", serde(alias = "version"))]
-    pkg_version: &'a str,
-    pub(crate) hash: hashes::ItemHash<Blake2s>,
+    pub(crate) pkg_version: &'a str,
+    pub(crate) hash: hashes::ItemHash,
+    /// How the output directory is serialized before hashing. Build files
+    /// that don't say are taken to use the original scheme, V0, so that the
+    /// hashes in recipes written before this field existed still verify.
+    /// This differs on purpose from [Package::new] and
+    /// [DirHashVersion::default], which use the current scheme for packages
+    /// made from code.
+    #[cfg_attr(feature = "serde", serde(default = "legacy_hash_version"))]
+    pub(crate) hash_version: DirHashVersion,
+    /// The outputs besides the main one. The store path of each is named as
+    /// if `<package_name>.<output>` were a package of its own, which is also
+    /// how dependents name it.
+    #[cfg_attr(feature = "serde", serde(default))]
+    #[cfg_attr(feature = "serde", serde(borrow))]
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Vec::is_empty"))]
+    pub(crate) outputs: Vec<Output<'a>>,
     #[cfg_attr(feature = "serde", serde(rename = "dependencies"))]
     #[cfg_attr(feature = "serde", serde(default))]
     #[cfg_attr(feature = "serde", serde(borrow))]
@@ -42,20 +79,34 @@ pub struct Package<'a> {
     #[cfg_attr(feature = "serde", serde(default))]
     #[cfg_attr(feature = "serde", serde(borrow))]
     pub(crate) build_settings: HashMap<&'a str, &'a str>,
+    /// Where to find the build file for this package, should it need to be
+    /// built before whatever depends on it.
+    #[cfg_attr(feature = "serde", serde(default))]
+    #[cfg_attr(feature = "serde", serde(with = "url_serde::opt"))]
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub(crate) recipe: Option<Url>,
+}
+
+#[cfg(feature = "serde")]
+fn legacy_hash_version() -> DirHashVersion {
+    DirHashVersion::V0
 }
 
 impl<'a> Package<'a> {
     pub fn new(
         pkg_name: &'a str,
         pkg_version: &'a str,
-        hash: hashes::ItemHash<Blake2s>
+        hash: hashes::ItemHash
     ) -> Self {
         Package {
             pkg_name,
             pkg_version,
             hash,
+            hash_version: DirHashVersion::default(),
+            outputs: Vec::new(),
             deps: Vec::new(),
             build_settings: HashMap::new(),
+            recipe: None,
         }
     }
 
@@ -66,6 +117,13 @@ impl<'a> Package<'a> {
         self
     }
 
+    pub fn add_outputs<I>(&mut self, iter: I) -> &mut Self
+        where I: IntoIterator<Item = Output<'a>>
+    {
+        self.outputs.extend(iter);
+        self
+    }
+
     pub fn add_build_settings<I>(&mut self, iter: I) -> &mut Self
         where I: IntoIterator<Item = (&'a str, &'a str)>
     {
@@ -73,12 +131,68 @@ impl<'a> Package<'a> {
         self
     }
 
+    pub fn hash(&self) -> &hashes::ItemHash {
+        &self.hash
+    }
+
+    pub fn hash_version(&self) -> DirHashVersion {
+        self.hash_version
+    }
+
+    pub fn set_hash_version(&mut self, version: DirHashVersion) -> &mut Self {
+        self.hash_version = version;
+        self
+    }
+
+    pub fn set_recipe(&mut self, recipe: Url) -> &mut Self {
+        self.recipe = Some(recipe);
+        self
+    }
+
+    pub fn recipe(&self) -> Option<&Url> {
+        self.recipe.as_ref()
+    }
+
     pub fn pkg_ident(&self) -> String {
-        let mut ident = format!("{}-{}-", self.pkg_name, self.pkg_version);
-        BASE32_NOPAD.encode_append(&self.hash.as_ref(), &mut ident);
+        self.ident_with(MAIN_OUTPUT, &self.hash)
+    }
+
+    fn ident_with(&self, output: &str, hash: &hashes::ItemHash) -> String {
+        let mut ident = if output == MAIN_OUTPUT {
+            format!("{}-{}-", self.pkg_name, self.pkg_version)
+        } else {
+            format!("{}.{}-{}-", self.pkg_name, output, self.pkg_version)
+        };
+        BASE32_NOPAD.encode_append(hash.as_ref(), &mut ident);
         ident
     }
 
+    /// The names of the outputs of the package, starting with [MAIN_OUTPUT].
+    pub fn output_names(&self) -> impl Iterator<Item = &'a str> + '_ {
+        std::iter::once(MAIN_OUTPUT).chain(self.outputs.iter().map(|o| o.name))
+    }
+
+    pub fn output_hash(&self, output: &str) -> Option<&hashes::ItemHash> {
+        if output == MAIN_OUTPUT {
+            return Some(&self.hash);
+        }
+        self.outputs.iter().find(|o| o.name == output).map(|o| &o.hash)
+    }
+
+    pub(crate) fn set_output_hash(&mut self, output: &str, hash: hashes::ItemHash) {
+        if output == MAIN_OUTPUT {
+            self.hash = hash;
+        } else if let Some(o) = self.outputs.iter_mut().find(|o| o.name == output) {
+            o.hash = hash;
+        }
+    }
+
+    /// The `pkg_ident` of `output`, which is that of the package itself for
+    /// [MAIN_OUTPUT].
+    pub fn output_ident(&self, output: &str) -> Option<String> {
+        self.output_hash(output).map(|hash| self.ident_with(output, hash))
+    }
+
     pub fn is_installed(&self, pkg_store_dir: &mut PathBuf) -> bool {
         let ident = self.pkg_ident();
         pkg_store_dir.push(ident);
@@ -106,4 +220,50 @@ mod tests {
             "test-1.0.0-GNC4RH2YRCDAH7AHVIISWYE2JSD3PJXAQTRCMTGQLXJRULOJKI5A"
         );
     }
+
+    #[test]
+    fn test_output_ident() {
+        let mut pkg = Package::new(
+            "test",
+            "1.0.0",
+            Blake2s::digest(b"hello_world").into()
+        );
+        pkg.add_outputs([Output::new("dev", Blake2s::digest(b"hello_world").into())]);
+        assert_eq!(pkg.output_names().collect::<Vec<_>>(), ["out", "dev"]);
+        assert_eq!(pkg.output_ident(MAIN_OUTPUT), Some(pkg.pkg_ident()));
+        assert_eq!(
+            pkg.output_ident("dev").unwrap().as_str(),
+            "test.dev-1.0.0-GNC4RH2YRCDAH7AHVIISWYE2JSD3PJXAQTRCMTGQLXJRULOJKI5A"
+        );
+        assert_eq!(pkg.output_ident("doc"), None);
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn test_default_hash_version() {
+        use std::fs;
+        use std::process;
+        use crate::walk_dir::calculate_directory_hash;
+
+        let dir = std::env::temp_dir()
+            .join(format!("yafpm_test_hash_version_{}", process::id()));
+        fs::create_dir_all(dir.join("bin")).unwrap();
+        fs::write(dir.join("bin/hello"), b"hello").unwrap();
+        let mut hasher = hashes::HashAlgo::Blake2s.hasher();
+        calculate_directory_hash(&dir, DirHashVersion::V0, &mut hasher).unwrap();
+        let v0_hash = hasher.finish();
+
+        let recipe = format!(
+            "package_name = \"test\"\npackage_version = \"1.0.0\"\nhash = \"{}\"\n",
+            v0_hash
+        );
+        let pkg: Package = toml::from_str(&recipe).unwrap();
+        assert_eq!(pkg.hash_version(), DirHashVersion::V0);
+        let version = pkg.hash_version();
+        let verified = pkg.hash.verify_hash_from_fn(
+            |d, h| calculate_directory_hash(d, version, h),
+            &dir);
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(verified.is_ok());
+    }
 }
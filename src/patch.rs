@@ -0,0 +1,440 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+// 
+// Copyright (C) 2021 John Arnold
+//
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::resource::{FetchOpts, Resource, ResourceError};
+use crate::unpack::safe_path;
+
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
+
+#[derive(Debug, thiserror::Error)]
+pub enum PatchError {
+    #[error("IO error while patching {}", .file.display())]
+    IOError {
+        #[source]
+        err: io::Error,
+        file: PathBuf
+    },
+    #[error("Line {line} of the patch is not valid unified diff: {msg}")]
+    ParseError {
+        line: usize,
+        msg: &'static str
+    },
+    #[error("Patch names {}, which is not a safe path after stripping {strip} components",
+            .path.display())]
+    PathError {
+        path: PathBuf,
+        strip: usize
+    },
+    #[error("Hunk {hunk} of {} does not apply (expected at line {line})", .file.display())]
+    HunkError {
+        file: PathBuf,
+        hunk: usize,
+        line: usize
+    },
+    #[error("Unable to fetch patch")]
+    FetchError(#[source] ResourceError),
+    #[error("Patch applies to {0}, which is not a resource")]
+    TargetError(String),
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+/// A unified diff to apply to one of the resources of a
+/// [BuildCxt](crate::BuildCxt) before building.
+pub struct Patch<'a> {
+    #[cfg_attr(feature = "serde", serde(flatten))]
+    #[cfg_attr(feature = "serde", serde(borrow))]
+    resource: Resource<'a>,
+    /// The name of the resource to patch, which is usually a directory that
+    /// an archive was unpacked into.
+    apply_to: &'a str,
+    /// How many leading components to remove from the paths in the patch.
+    #[cfg_attr(feature = "serde", serde(default = "default_strip"))]
+    strip: usize,
+}
+
+fn default_strip() -> usize {
+    1
+}
+
+impl<'a> Patch<'a> {
+    pub fn new(resource: Resource<'a>, apply_to: &'a str) -> Self {
+        Patch { resource, apply_to, strip: default_strip() }
+    }
+
+    pub fn set_strip(&mut self, strip: usize) -> &mut Self {
+        self.strip = strip;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        self.resource.name()
+    }
+
+    pub(crate) fn resource(&self) -> &Resource<'a> {
+        &self.resource
+    }
+
+    /// Fetches the patch and applies it to the resource it names, which
+    /// must already be in `build_dir`. The patch itself is fetched into a
+    /// directory of its own in `build_dir`, which is removed afterwards.
+    pub(crate) fn apply(
+        &self,
+        build_dir: &Path,
+        opts: &FetchOpts
+    ) -> Result<(), PatchError> {
+        let target = match safe_path(Path::new(self.apply_to)) {
+            Some(p) if !p.as_os_str().is_empty() => build_dir.join(p),
+            _ => return Err(PatchError::TargetError(self.apply_to.to_string())),
+        };
+        if !target.exists() {
+            return Err(PatchError::TargetError(self.apply_to.to_string()));
+        }
+        let patch_dir = build_dir.join(".patch");
+        fs::create_dir(&patch_dir).map_err(
+            |e| PatchError::IOError{err: e, file: patch_dir.clone()})?;
+        let res = self.resource.fetch_resource(&patch_dir, opts)
+            .map_err(PatchError::FetchError)
+            .and_then(|_| {
+                let file = patch_dir.join(self.resource.name());
+                fs::read_to_string(&file).map_err(
+                    |e| PatchError::IOError{err: e, file})
+            })
+            .and_then(|patch| apply_patch(&patch, &target, self.strip));
+        let _ = fs::remove_dir_all(&patch_dir);
+        res
+    }
+}
+
+// A line of a file, including its line ending if it has one
+type Line = String;
+
+struct Hunk {
+    // Where the hunk says it starts, counting from 1
+    old_start: usize,
+    old: Vec<Line>,
+    new: Vec<Line>,
+}
+
+struct FilePatch {
+    old_path: Option<PathBuf>,
+    new_path: Option<PathBuf>,
+    hunks: Vec<Hunk>,
+}
+
+// Reads the path from a `---` or `+++` line, or None for /dev/null
+fn header_path(rest: &str) -> Option<PathBuf> {
+    // Anything after a tab is a timestamp
+    let path = rest.split('\t').next().unwrap_or("").trim_end();
+    if path == "/dev/null" {
+        None
+    } else {
+        Some(PathBuf::from(path))
+    }
+}
+
+// Parses "<start>[,<count>]", where a missing count means 1
+fn parse_range(range: &str) -> Option<(usize, usize)> {
+    let mut parts = range.splitn(2, ',');
+    let start = parts.next()?.parse().ok()?;
+    let count = match parts.next() {
+        Some(c) => c.parse().ok()?,
+        None => 1,
+    };
+    Some((start, count))
+}
+
+fn parse_hunk_header(line: &str) -> Option<(usize, usize, usize)> {
+    let mut parts = line.strip_prefix("@@ -")?.split(' ');
+    let (old_start, old_count) = parse_range(parts.next()?)?;
+    let (_, new_count) = parse_range(parts.next()?.strip_prefix('+')?)?;
+    Some((old_start, old_count, new_count))
+}
+
+fn parse(patch: &str) -> Result<Vec<FilePatch>, PatchError> {
+    let lines: Vec<&str> = patch.split_inclusive('\n').collect();
+    let mut files: Vec<FilePatch> = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        let err = |msg| PatchError::ParseError{line: i + 1, msg};
+        if let Some(rest) = line.strip_prefix("--- ") {
+            let next = lines.get(i + 1).and_then(|l| l.strip_prefix("+++ "))
+                .ok_or_else(|| err("`---` line not followed by a `+++` line"))?;
+            files.push(FilePatch {
+                old_path: header_path(rest),
+                new_path: header_path(next),
+                hunks: Vec::new(),
+            });
+            i += 2;
+        } else if line.starts_with("@@ ") {
+            let (old_start, mut old_left, mut new_left) = parse_hunk_header(line)
+                .ok_or_else(|| err("malformed hunk header"))?;
+            // Only a hunk that adds lines to the top of a file starts at 0
+            if old_start == 0 && old_left > 0 {
+                return Err(err("hunk with old lines starts at line 0"));
+            }
+            let file = files.last_mut()
+                .ok_or_else(|| err("hunk before any file header"))?;
+            let mut hunk = Hunk { old_start, old: Vec::new(), new: Vec::new() };
+            i += 1;
+            // Which of old and new the last line went to, for "\ No newline"
+            let mut last = (false, false);
+            while old_left > 0
+                || new_left > 0
+                || lines.get(i).is_some_and(|l| l.starts_with('\\'))
+            {
+                let line = *lines.get(i).ok_or_else(|| PatchError::ParseError{
+                    line: i + 1,
+                    msg: "patch ends in the middle of a hunk"
+                })?;
+                // Some tools drop the space from empty context lines
+                let (kind, text) = match line {
+                    "\n" => (' ', "\n"),
+                    _ => (line.as_bytes()[0] as char, line.get(1..).unwrap_or("")),
+                };
+                match kind {
+                    ' ' if old_left > 0 && new_left > 0 => {
+                        hunk.old.push(text.to_string());
+                        hunk.new.push(text.to_string());
+                        old_left -= 1;
+                        new_left -= 1;
+                        last = (true, true);
+                    }
+                    '-' if old_left > 0 => {
+                        hunk.old.push(text.to_string());
+                        old_left -= 1;
+                        last = (true, false);
+                    }
+                    '+' if new_left > 0 => {
+                        hunk.new.push(text.to_string());
+                        new_left -= 1;
+                        last = (false, true);
+                    }
+                    '\\' => {
+                        if last.0 {
+                            hunk.old.last_mut().map(|l| l.pop());
+                        }
+                        if last.1 {
+                            hunk.new.last_mut().map(|l| l.pop());
+                        }
+                    }
+                    _ => return Err(PatchError::ParseError{
+                        line: i + 1,
+                        msg: "line doesn't fit the hunk"
+                    }),
+                }
+                i += 1;
+            }
+            file.hunks.push(hunk);
+        } else {
+            // Anything else, like "diff --git" or "index" lines, or text
+            // before the first file, carries nothing we need
+            i += 1;
+        }
+    }
+    Ok(files)
+}
+
+fn strip_path(path: &Path, strip: usize) -> Result<PathBuf, PatchError> {
+    let path_err = || PatchError::PathError{path: path.into(), strip};
+    let stripped: PathBuf = path.components().skip(strip).collect();
+    match safe_path(&stripped) {
+        Some(p) if !p.as_os_str().is_empty() => Ok(p),
+        _ => Err(path_err()),
+    }
+}
+
+// Checks that nothing on the way to dir/rel is a symlink, and that rel itself
+// is a regular file if it exists, so that patching can't read or write
+// anything outside of dir. Once a component is missing, nothing below it can
+// exist either.
+fn check_path(dir: &Path, rel: &Path, path_err: impl Fn() -> PatchError)
+    -> Result<PathBuf, PatchError>
+{
+    let mut cur = dir.to_path_buf();
+    let mut comps = rel.components().peekable();
+    while let Some(comp) = comps.next() {
+        cur.push(comp);
+        let is_last = comps.peek().is_none();
+        match fs::symlink_metadata(&cur) {
+            Ok(meta) if is_last && meta.is_file() => (),
+            Ok(meta) if !is_last && meta.is_dir() => (),
+            Ok(_) => return Err(path_err()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+            Err(e) => return Err(PatchError::IOError{err: e, file: rel.into()}),
+        }
+    }
+    Ok(dir.join(rel))
+}
+
+// Finds where the hunk's old lines are in the file, preferring the place
+// closest to where the hunk says they are. Nothing before `min` is looked at,
+// since earlier hunks have already been applied there.
+fn find_hunk(lines: &[Line], old: &[Line], expected: usize, min: usize) -> Option<usize> {
+    let fits = |pos: usize| pos >= min
+        && pos + old.len() <= lines.len()
+        && lines[pos..pos + old.len()] == *old;
+    let expected = expected.max(min);
+    for dist in 0..=lines.len() {
+        if fits(expected + dist) {
+            return Some(expected + dist);
+        }
+        if dist <= expected && fits(expected - dist) {
+            return Some(expected - dist);
+        }
+    }
+    None
+}
+
+fn apply_file(dir: &Path, file: &FilePatch, strip: usize) -> Result<(), PatchError> {
+    let (header, rel) = match (&file.new_path, &file.old_path) {
+        (Some(p), _) | (None, Some(p)) => (p, strip_path(p, strip)?),
+        (None, None) => return Ok(()),
+    };
+    let path = check_path(dir, &rel, || PatchError::PathError{path: header.into(), strip})?;
+    let io_err = |e| PatchError::IOError{err: e, file: rel.clone()};
+    let mut lines: Vec<Line> = if file.old_path.is_some() {
+        let contents = fs::read_to_string(&path).map_err(io_err)?;
+        contents.split_inclusive('\n').map(String::from).collect()
+    } else {
+        Vec::new()
+    };
+
+    // Where the next hunk may start in lines, and how far the hunks so far
+    // have moved later lines from where the patch expects them
+    let mut min = 0;
+    let mut offset: isize = 0;
+    for (n, hunk) in file.hunks.iter().enumerate() {
+        // A hunk that only adds lines says it starts at the line before them
+        let start = if hunk.old.is_empty() { hunk.old_start } else { hunk.old_start - 1 };
+        let expected = (start as isize + offset).max(0) as usize;
+        let pos = find_hunk(&lines, &hunk.old, expected, min).ok_or_else(
+            || PatchError::HunkError{file: rel.clone(), hunk: n + 1, line: hunk.old_start})?;
+        lines.splice(pos..pos + hunk.old.len(), hunk.new.iter().cloned());
+        min = pos + hunk.new.len();
+        offset += hunk.new.len() as isize - hunk.old.len() as isize + pos as isize - expected as isize;
+    }
+
+    if file.new_path.is_none() {
+        return fs::remove_file(&path).map_err(io_err);
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(io_err)?;
+    }
+    // fs::write keeps the mode of a file that's already there
+    fs::write(&path, lines.concat()).map_err(io_err)
+}
+
+/// Applies the unified diff `patch` to the files under `dir`, after removing
+/// `strip` leading components from each path it names, as `patch -p<strip>`
+/// would. Hunks must match exactly, but may be found away from the line
+/// they give.
+pub(crate) fn apply_patch(patch: &str, dir: &Path, strip: usize) -> Result<(), PatchError> {
+    for file in parse(patch)? {
+        apply_file(dir, &file, strip)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    const ORIGINAL: &str = "one\ntwo\nthree\nfour\nfive\nsix\nseven\neight\nnine\n";
+
+    const PATCH: &str = "\
+diff --git a/src/numbers b/src/numbers
+--- a/src/numbers\t2021-01-01 00:00:00
++++ b/src/numbers\t2021-01-01 00:00:00
+@@ -1,3 +1,3 @@
+-one
++ONE
+ two
+ three
+@@ -7,3 +7,4 @@
+ seven
+ eight
++eight and a half
+ nine
+--- /dev/null
++++ b/src/new
+@@ -0,0 +1 @@
++new file
+\\ No newline at end of file
+";
+
+    #[test]
+    fn test_apply_patch() {
+        let dir = env::temp_dir().join(format!("yafpm-patch-test-{}", process::id()));
+        fs::create_dir_all(dir.join("src")).unwrap();
+        // An extra line at the top moves every hunk down by one
+        fs::write(dir.join("src/numbers"), format!("zero\n{}", ORIGINAL)).unwrap();
+        apply_patch(PATCH, &dir, 1).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join("src/numbers")).unwrap(),
+            "zero\nONE\ntwo\nthree\nfour\nfive\nsix\nseven\neight\neight and a half\nnine\n"
+        );
+        assert_eq!(fs::read_to_string(dir.join("src/new")).unwrap(), "new file");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_hunk_error() {
+        let dir = env::temp_dir().join(format!("yafpm-patch-fail-test-{}", process::id()));
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join("src/numbers"), ORIGINAL.replace("eight", "ate")).unwrap();
+        match apply_patch(PATCH, &dir, 1) {
+            Err(PatchError::HunkError{hunk, line, ..}) => assert_eq!((hunk, line), (2, 7)),
+            _ => panic!("second hunk should not apply"),
+        }
+        assert!(matches!(apply_patch(PATCH, &dir, 3), Err(PatchError::PathError{..})));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_symlinked_path() {
+        let dir = env::temp_dir().join(format!("yafpm-patch-symlink-test-{}", process::id()));
+        let outside = dir.join("outside");
+        fs::create_dir_all(outside.join("src")).unwrap();
+        fs::write(outside.join("src/numbers"), ORIGINAL).unwrap();
+        let src = dir.join("src");
+        fs::create_dir(&src).unwrap();
+        // Neither a symlinked directory nor a symlinked file is followed
+        std::os::unix::fs::symlink(outside.join("src"), src.join("src")).unwrap();
+        assert!(matches!(apply_patch(PATCH, &src, 1), Err(PatchError::PathError{..})));
+        fs::remove_file(src.join("src")).unwrap();
+        fs::create_dir(src.join("src")).unwrap();
+        std::os::unix::fs::symlink(outside.join("src/numbers"), src.join("src/numbers"))
+            .unwrap();
+        assert!(matches!(apply_patch(PATCH, &src, 1), Err(PatchError::PathError{..})));
+        assert_eq!(fs::read_to_string(outside.join("src/numbers")).unwrap(), ORIGINAL);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_zero_start() {
+        let patch = "--- a/numbers\n+++ b/numbers\n@@ -0,1 +0,1 @@\n-one\n+ONE\n";
+        assert!(matches!(parse(patch), Err(PatchError::ParseError{line: 3, ..})));
+    }
+}
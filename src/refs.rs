@@ -0,0 +1,148 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+// 
+// Copyright (C) 2021 John Arnold
+//
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! Finding which store paths the output of a build refers to.
+//!
+//! An output refers to a package if the base32 hash at the end of the
+//! package's `pkg_ident` turns up in it, whether in the contents of a file or
+//! the target of a symlink. The hash has to be a whole run of base32
+//! characters, which it is in a path, where it's followed by a `/` if by
+//! anything. Compressed files are not looked into.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+fn is_base32(b: u8) -> bool {
+    matches!(b, b'A'..=b'Z' | b'2'..=b'7')
+}
+
+struct Scanner<'a> {
+    // The idents looked for, by their hashes
+    hashes: HashMap<&'a [u8], &'a str>,
+    longest: usize,
+    // The run of base32 characters read so far, unless it's already longer
+    // than any hash
+    run: Vec<u8>,
+    too_long: bool,
+    found: BTreeSet<&'a str>,
+}
+
+impl<'a> Scanner<'a> {
+    fn new(idents: &'a [String]) -> Self {
+        let hashes: HashMap<_, _> = idents.iter()
+            .filter_map(|i| i.rsplit('-').next().map(|h| (h.as_bytes(), i.as_str())))
+            .collect();
+        let longest = hashes.keys().map(|h| h.len()).max().unwrap_or(0);
+        Scanner {
+            hashes,
+            longest,
+            run: Vec::with_capacity(longest),
+            too_long: false,
+            found: BTreeSet::new(),
+        }
+    }
+
+    fn feed(&mut self, buf: &[u8]) {
+        for &b in buf {
+            if !is_base32(b) {
+                self.end_run();
+            } else if self.run.len() < self.longest {
+                self.run.push(b);
+            } else {
+                self.too_long = true;
+            }
+        }
+    }
+
+    fn end_run(&mut self) {
+        if !self.too_long && !self.run.is_empty() {
+            if let Some(ident) = self.hashes.get(&self.run[..]) {
+                self.found.insert(ident);
+            }
+        }
+        self.run.clear();
+        self.too_long = false;
+    }
+
+    fn scan_file(&mut self, path: &Path) -> Result<(), io::Error> {
+        let mut file = File::open(path)?;
+        let mut buf = [0; 64 * 1024];
+        loop {
+            match file.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => self.feed(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        self.end_run();
+        Ok(())
+    }
+
+    fn scan_dir(&mut self, dir: &Path) -> Result<(), io::Error> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let file_type = fs::symlink_metadata(&path)?.file_type();
+            if file_type.is_dir() {
+                self.scan_dir(&path)?;
+            } else if file_type.is_symlink() {
+                self.feed(fs::read_link(&path)?.as_os_str().as_bytes());
+                self.end_run();
+            } else if file_type.is_file() {
+                self.scan_file(&path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Finds which of the packages `idents` the files in `dir` refer to, in
+/// sorted order.
+pub(crate) fn scan_refs(dir: &Path, idents: &[String]) -> Result<Vec<String>, io::Error> {
+    let mut scanner = Scanner::new(idents);
+    scanner.scan_dir(dir)?;
+    Ok(scanner.found.into_iter().map(str::to_string).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan_refs() {
+        let idents = [
+            "a-1.0-GNC4RH2YRCDAH7AHVIISWYE2JSD3PJXAQTRCMTGQLXJRULOJKI5A".to_string(),
+            "b-1.0-BOISPQ2PK2FQYQO7VDHHIIK36PSLM3O5MIIVMPQ7HYGOF6NNQGKA".to_string(),
+            "c-1.0-KDFYPSMCW2R2JHNSR3I5WLEOADFCEBZXOUI5PMCHLAIZZQRM3N6Q".to_string(),
+        ];
+        let dir = std::env::temp_dir().join(format!("yafpm-refs-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("script"), format!("#!/store/{}/bin/sh\n", idents[0])).unwrap();
+        // Part of a longer run of base32 characters, so not a reference
+        fs::write(dir.join("sub/data"), format!("X{}", &idents[2][6..])).unwrap();
+        std::os::unix::fs::symlink(format!("/store/{}/lib", idents[1]), dir.join("sub/link"))
+            .unwrap();
+        let found = scan_refs(&dir, &idents).unwrap();
+        assert_eq!(found, &idents[..2]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -16,17 +16,35 @@
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
 use std::fs;
+use std::fmt;
 use std::io;
+use std::io::Seek;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::process::{Command, ExitStatus};
 use url::Url;
-use blake2::Blake2s;
 
+use crate::dirs;
 use crate::hashes;
+use crate::walk_dir::{self, DirHashVersion};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use crate::unpack::{self, ArchiveFormat, UnpackError};
 
 #[cfg(feature = "serde")]
 use serde::{Serialize, Deserialize};
+
+/// The directory in the package store where `yafpm-build` and friends keep
+/// fetched resources, unless told otherwise.
+pub const CACHE_DIR: &str = ".yafpm-cache";
+
+/// The file in the package store where `yafpm-build` and friends look for
+/// mirror sets, unless told otherwise. See [FetchOpts::load_mirrors].
+pub const MIRRORS_FILE: &str = ".yafpm-mirrors";
+
 #[cfg(feature = "minreq")]
-use minreq;
+const CHUNK_SIZE: usize = 64 * 1024;
 
 #[derive(Debug, thiserror::Error)]
 pub enum ResourceError {
@@ -47,13 +65,59 @@ pub enum ResourceError {
     HTTPError {
         #[source]
         err: minreq::Error,
-        url: Url
+        url: Box<Url>
     },
     #[cfg(feature = "minreq")]
-    #[error("Received HTTP response {} from {url}", response.status_code)]
+    #[error("Received HTTP response {status} {reason} from {url}")]
     HTTPStatus {
-        url: Url,
-        response: minreq::Response
+        url: Box<Url>,
+        status: i32,
+        reason: String
+    },
+    #[error("Unable to unpack resource {name}")]
+    UnpackError {
+        #[source]
+        err: UnpackError,
+        name: String
+    },
+    #[error("Resource {name} uses mirror {mirror}, which is not defined")]
+    UnknownMirror {
+        name: String,
+        mirror: String,
+    },
+    #[error("Unable to fetch resource {name} from any of its URLs:{}",
+            attempts.iter().map(|(url, e)| format!("\n        {}: {}", url, error_chain(e)))
+                    .collect::<String>())]
+    AllURLsFailed {
+        name: String,
+        attempts: Vec<(Url, ResourceError)>,
+    },
+    #[error("Resource {name} has an invalid exclude pattern")]
+    ExcludeError {
+        #[source]
+        err: globset::Error,
+        name: String
+    },
+    #[error("Git URL {url} does not name a commit by its full hash")]
+    GitRevError {
+        url: Box<Url>,
+    },
+    #[error("Unable to run git for {url}")]
+    GitExecError {
+        #[source]
+        err: io::Error,
+        url: Box<Url>,
+    },
+    #[error("git {action} failed for {url}: {status}")]
+    GitError {
+        url: Box<Url>,
+        action: &'static str,
+        status: ExitStatus,
+    },
+    #[error("Line {line} of mirrors file {} is not of the form `<name> <url>`", .file.display())]
+    MirrorsFileError {
+        file: PathBuf,
+        line: usize,
     },
     #[error("Resource {name} has unrecognized URL scheme: {scheme}")]
     Unrecognized{
@@ -62,76 +126,566 @@ pub enum ResourceError {
     }
 }
 
+// Error messages that list several errors need to show each one's sources
+// inline.
+fn error_chain(err: &dyn std::error::Error) -> String {
+    let mut s = err.to_string();
+    let mut source = err.source();
+    while let Some(e) = source {
+        s.push_str(": ");
+        s.push_str(&e.to_string());
+        source = e.source();
+    }
+    s
+}
+
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 /// A file that is used in the building of a package.
 pub struct Resource<'a> {
     #[cfg_attr(feature = "serde", serde(borrow))]
     name: &'a str,
-    hash: hashes::ItemHash<Blake2s>,
-    #[cfg_attr(feature = "serde", serde(with = "url_serde"))]
-    url: Url,
+    hash: hashes::ItemHash,
+    /// Where the file can be fetched from, tried in order until one of them
+    /// gives a file with the right hash. In recipes, `url` can be either a
+    /// single URL or a list of them. A `mirror://<set>/<path>` URL stands
+    /// for `<path>` under each URL of the named mirror set, as given by
+    /// [FetchOpts::add_mirror].
+    ///
+    /// A `file` URL may name a directory, which is copied into a directory
+    /// called `name`, leaving out whatever `exclude` matches. The hash is
+    /// then that of the copy, as [DirHashVersion::V1] hashes it.
+    ///
+    /// A `git+<url>?rev=<commit>` URL is a git repository, of which the tree
+    /// at `<commit>` is checked out into a directory called `name`. The hash
+    /// is then that of the directory, as [DirHashVersion::V1] hashes it.
+    #[cfg_attr(feature = "serde", serde(rename = "url", alias = "urls"))]
+    #[cfg_attr(feature = "serde", serde(with = "url_serde::one_or_many"))]
+    urls: Vec<Url>,
+    /// If set, the file is an archive of this format, which is extracted into
+    /// a directory called `name` instead of being copied. The hash is still
+    /// that of the archive itself.
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    unpack: Option<ArchiveFormat>,
+    /// If the URL is a local directory, glob patterns for what to leave out
+    /// of it, such as `target/` or `*.o`. As with `.gitignore` files, a
+    /// pattern without a `/` matches anywhere in the tree, one with a `/`
+    /// matches from the top of the directory, and one ending in `/` only
+    /// matches directories.
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Vec::is_empty"))]
+    #[cfg_attr(feature = "serde", serde(borrow))]
+    exclude: Vec<&'a str>,
+    /// If set, a single file resource is given mode 755 or 644 once fetched,
+    /// whatever mode it was fetched with. Since the mode matters as much as
+    /// the contents, the hash is then that of the file as
+    /// [DirHashVersion::V1] hashes it, which covers whether it is
+    /// executable. If not set, the file keeps the mode it was fetched with,
+    /// and the hash is that of its contents only.
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    executable: Option<bool>,
 }
 
 impl<'a> Resource<'a> {
-    pub fn new (name: &'a str, hash: hashes::ItemHash<Blake2s>, url: Url) -> Self {
-        Resource { name, hash, url }
+    pub fn new (name: &'a str, hash: hashes::ItemHash, url: Url) -> Self {
+        Resource {
+            name,
+            hash,
+            urls: vec![url],
+            unpack: None,
+            exclude: Vec::new(),
+            executable: None,
+        }
+    }
+
+    /// Adds URLs to try if the ones already given fail.
+    pub fn add_urls<I>(&mut self, iter: I) -> &mut Self
+        where I: IntoIterator<Item = Url>
+    {
+        self.urls.extend(iter);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        self.name
+    }
+
+    pub fn set_unpack(&mut self, format: ArchiveFormat) -> &mut Self {
+        self.unpack = Some(format);
+        self
+    }
+
+    pub fn set_executable(&mut self, executable: bool) -> &mut Self {
+        self.executable = Some(executable);
+        self
+    }
+
+    pub fn add_excludes<I>(&mut self, iter: I) -> &mut Self
+        where I: IntoIterator<Item = &'a str>
+    {
+        self.exclude.extend(iter);
+        self
+    }
+
+    // Returns the exclude patterns as two sets, for those that match anything
+    // and those that only match directories
+    fn exclude_globs(&self) -> Result<(GlobSet, GlobSet), ResourceError> {
+        let mut any = GlobSetBuilder::new();
+        let mut dirs_only = GlobSetBuilder::new();
+        for pattern in &self.exclude {
+            let (pattern, builder) = match pattern.strip_suffix('/') {
+                Some(p) => (p, &mut dirs_only),
+                None => (*pattern, &mut any),
+            };
+            let pattern = match pattern.strip_prefix('/') {
+                Some(p) => p.to_string(),
+                None if pattern.contains('/') => pattern.to_string(),
+                None => format!("**/{}", pattern),
+            };
+            let glob = GlobBuilder::new(&pattern).literal_separator(true).build()
+                .map_err(|e| ResourceError::ExcludeError{err: e, name: self.name.to_string()})?;
+            builder.add(glob);
+        }
+        let build = |b: GlobSetBuilder| b.build().map_err(
+            |e| ResourceError::ExcludeError{err: e, name: self.name.to_string()});
+        Ok((build(any)?, build(dirs_only)?))
+    }
+
+    fn unpack_into<R: io::Read + Seek>(
+        &self,
+        format: ArchiveFormat,
+        reader: R,
+        build_dir: &Path
+    ) -> Result<(), ResourceError> {
+        unpack::unpack(format, reader, &build_dir.join(self.name)).map_err(
+            |e| ResourceError::UnpackError{err: e, name: self.name.to_string()})
     }
 
     fn verify_hash(&self, fd: &mut fs::File) -> Result <u64, hashes::HashError> {
         self.hash.verify_hash_from_fn(io::copy, fd)
     }
 
-    fn fetch_file<P: AsRef<Path>>(
-        &self,
-        build_dir: P,
-    ) -> Result <(), ResourceError> {
-        let src_path = Path::new(self.url.path());
-        let mut file = fs::File::open(src_path).map_err(
-            |e| ResourceError::IOError{err: e, file: PathBuf::from(src_path)})?;
+    fn verify_file(&self, path: &Path) -> Result<fs::File, ResourceError> {
+        let mut file = fs::File::open(path).map_err(
+            |e| ResourceError::IOError{err: e, file: path.into()})?;
         self.verify_hash(&mut file).map_err(
             |e| ResourceError::HashError{err: e, name: self.name.to_string()})?;
-        let target = build_dir.as_ref().join(self.name);
+        Ok(file)
+    }
+
+    fn fetch_file(&self, url: &Url, target: &Path) -> Result <(), ResourceError> {
+        let src_path = Path::new(url.path());
+        if src_path.is_dir() {
+            return self.fetch_dir(src_path, target);
+        }
         fs::copy(src_path, target).map_err(
             |e| ResourceError::IOError{err: e, file: PathBuf::from(src_path)})?;
-        Ok(())
+        self.verify_fetched(target)
     }
 
+    // Checks a file that has just been fetched to target, first setting its
+    // mode if the resource gives one.
+    fn verify_fetched(&self, target: &Path) -> Result <(), ResourceError> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let executable = match self.executable {
+            Some(executable) => executable,
+            None => return self.verify_file(target).map(|_| ()),
+        };
+        let mode = if executable { 0o755 } else { 0o644 };
+        fs::set_permissions(target, fs::Permissions::from_mode(mode)).map_err(
+            |e| ResourceError::IOError{err: e, file: target.into()})?;
+        self.verify_dir(target)
+    }
+
+    // Streams the body to target, hashing it on the way, so that large
+    // downloads don't need to fit in memory.
     #[cfg(feature = "minreq")]
-    fn fetch_http<P: AsRef<Path>>(
+    fn fetch_http(
         &self,
-        build_dir: P,
+        url: &Url,
+        target: &Path,
+        opts: &FetchOpts
     ) -> Result <(), ResourceError> {
-        let response = minreq::get(self.url.as_str()).send().map_err(
-            |e| ResourceError::HTTPError{err: e, url: self.url.clone()})?;
+        use std::io::{Read, Write};
+
+        let http_err = |e| ResourceError::HTTPError{err: e, url: Box::new(url.clone())};
+        let io_err = |e| ResourceError::IOError{err: e, file: target.into()};
+        let mut response = minreq::get(url.as_str()).send_lazy().map_err(http_err)?;
         if response.status_code != 200 {
             return Err(ResourceError::HTTPStatus{
-                url: self.url.clone(),
-                response: response });
+                url: Box::new(url.clone()),
+                status: response.status_code,
+                reason: response.reason_phrase.clone(),
+            });
+        }
+        let total = response.headers.get("content-length")
+            .and_then(|l| l.parse().ok());
+        let mut file = io::BufWriter::new(
+            fs::File::create(target).map_err(io_err)?);
+        let mut hasher = self.hash.algo().hasher();
+        let mut received = 0;
+        let mut write_chunk = |chunk: &[u8]| -> Result<(), io::Error> {
+            file.write_all(chunk)?;
+            hasher.write_all(chunk)?;
+            received += chunk.len() as u64;
+            if let Some(progress) = &opts.progress {
+                progress(self.name, received, total);
+            }
+            Ok(())
+        };
+        let mut buf = vec![0; CHUNK_SIZE];
+        loop {
+            let len = match response.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(http_err(minreq::Error::IoError(e))),
+            };
+            write_chunk(&buf[..len]).map_err(io_err)?;
+        }
+        file.flush().map_err(io_err)?;
+
+        if self.executable.is_some() {
+            // The hash covers the mode, so what we just worked out is no use
+            return self.verify_fetched(target);
+        }
+        let found = hasher.finish();
+        if found != self.hash {
+            return Err(ResourceError::HashError{
+                err: hashes::HashError::BadHash{expected: self.hash.clone(), found},
+                name: self.name.to_string()
+            });
         }
-        self.hash.verify_hash_from_fn(io::copy, &mut response.as_bytes()).map_err(
-            |e| ResourceError::HashError{err: e, name: self.name.to_string()})?;
-        let target = build_dir.as_ref().join(self.name);
-        fs::write(&target, response.into_bytes()).map_err(
-            |e| ResourceError::IOError{err: e, file: target})?;
         Ok(())
     }
 
-    pub(crate) fn fetch_resource<P: AsRef<Path>>(
+    // Copies a local directory to target, leaving out anything excluded, and
+    // hashes the copy.
+    fn fetch_dir(&self, src_path: &Path, target: &Path) -> Result <(), ResourceError> {
+        let (any, dirs_only) = self.exclude_globs()?;
+        let skip = |rel: &Path, is_dir: bool|
+            any.is_match(rel) || (is_dir && dirs_only.is_match(rel));
+        remove_part(target);
+        dirs::copy_dir_filtered(src_path, target, &skip)
+            .and_then(|_| dirs::normalize_mtimes(target))
+            .map_err(|e| ResourceError::IOError{err: e, file: src_path.into()})?;
+        self.verify_dir(target)
+    }
+
+    // Also used for single files whose hash covers their mode
+    fn verify_dir(&self, dir: &Path) -> Result <(), ResourceError> {
+        self.hash.verify_hash_from_fn(
+            |dir, h| walk_dir::calculate_directory_hash(dir, DirHashVersion::V1, h),
+            dir
+        ).map_err(|e| ResourceError::HashError{err: e, name: self.name.to_string()})?;
+        Ok(())
+    }
+
+    // Checks out the tree of a commit to target, without any git metadata.
+    // The URL is the repository's with `git+` in front, and the commit is
+    // given by its `rev` query parameter.
+    fn fetch_git(&self, url: &Url, target: &Path) -> Result <(), ResourceError> {
+        // Only a full object id names the same commit wherever the repo is
+        // cloned from, and it can't be taken for an option either
+        let rev = url.query_pairs().find(|(k, _)| k == "rev").map(|(_, v)| v)
+            .filter(|rev| is_object_id(rev))
+            .ok_or_else(|| ResourceError::GitRevError{url: Box::new(url.clone())})?;
+        let commit = format!("{}^{{commit}}", rev);
+        let mut repo = url.clone();
+        repo.set_query(None);
+        repo.set_fragment(None);
+        // Unwrap is fine, download_from only calls us for git+ schemes
+        let repo = repo.as_str().strip_prefix("git+").unwrap();
+
+        let git_dir = target.with_extension("git");
+        let git = |action: &'static str, args: &[&OsStr]| {
+            let status = Command::new("git")
+                .env("GIT_CONFIG_NOSYSTEM", "1")
+                .env("GIT_CONFIG_GLOBAL", "/dev/null")
+                .env("GIT_TERMINAL_PROMPT", "0")
+                .args(["-c", "core.autocrlf=false", "-c", "core.symlinks=true"])
+                .args(args)
+                .status()
+                .map_err(|e| ResourceError::GitExecError{err: e, url: Box::new(url.clone())})?;
+            if !status.success() {
+                return Err(ResourceError::GitError{url: Box::new(url.clone()), action, status});
+            }
+            Ok(())
+        };
+        remove_part(target);
+        remove_part(&git_dir);
+        fs::create_dir(target).map_err(
+            |e| ResourceError::IOError{err: e, file: target.into()})?;
+        let res = git("clone", &[
+            "clone".as_ref(), "--quiet".as_ref(), "--bare".as_ref(),
+            repo.as_ref(), git_dir.as_os_str()
+        ]).and_then(|_| git("checkout", &[
+            "--git-dir".as_ref(), git_dir.as_os_str(),
+            "--work-tree".as_ref(), target.as_os_str(),
+            "checkout".as_ref(), "--quiet".as_ref(), "--force".as_ref(),
+            commit.as_ref(), "--".as_ref(), ".".as_ref()
+        ]));
+        remove_part(&git_dir);
+        res?;
+
+        dirs::normalize_mtimes(target).map_err(
+            |e| ResourceError::IOError{err: e, file: target.into()})?;
+        self.verify_dir(target)
+    }
+
+    #[cfg_attr(not(feature = "minreq"), allow(unused_variables))]
+    fn download_from(
         &self,
-        build_dir: P
+        url: &Url,
+        target: &Path,
+        opts: &FetchOpts
     ) -> Result <(), ResourceError> {
-        match self.url.scheme() {
-            "file" =>  self.fetch_file(&build_dir),
+        match url.scheme() {
+            "file" =>  self.fetch_file(url, target),
+            scheme if scheme.starts_with("git+") => self.fetch_git(url, target),
             #[cfg(feature = "minreq")]
-            "http" => self.fetch_http(&build_dir),
+            "http" => self.fetch_http(url, target, opts),
             #[cfg(feature = "minreq-https")]
-            "https" => self.fetch_http(&build_dir),
+            "https" => self.fetch_http(url, target, opts),
             scheme =>  Err(ResourceError::Unrecognized{
                 scheme: scheme.to_string(),
                 name: self.name.to_string()
             })
         }
     }
+
+    // Fetches the resource to target from the first URL that works, and
+    // checks its hash. Callers have to remove target if this fails.
+    fn download(&self, target: &Path, opts: &FetchOpts) -> Result <(), ResourceError> {
+        let mut attempts = Vec::new();
+        for url in &self.urls {
+            let candidates = match opts.expand_mirror(url) {
+                Some(Ok(urls)) => urls,
+                Some(Err(mirror)) => {
+                    let e = ResourceError::UnknownMirror{
+                        name: self.name.to_string(),
+                        mirror
+                    };
+                    attempts.push((url.clone(), e));
+                    continue;
+                }
+                None => vec![url.clone()],
+            };
+            for candidate in candidates {
+                match self.download_from(&candidate, target, opts) {
+                    Ok(()) => return Ok(()),
+                    Err(e) => attempts.push((candidate, e)),
+                }
+            }
+        }
+        if attempts.len() == 1 {
+            // Unwrap is fine, we just checked the length
+            return Err(attempts.pop().unwrap().1);
+        }
+        Err(ResourceError::AllURLsFailed{name: self.name.to_string(), attempts})
+    }
+
+    // Puts the verified file at src into the build directory, unpacking it
+    // if need be.
+    fn install(&self, src: &Path, build_dir: &Path) -> Result <(), ResourceError> {
+        let target = build_dir.join(self.name);
+        if src.is_dir() {
+            return dirs::copy_dir_all(src, &target)
+                .and_then(|_| dirs::normalize_mtimes(&target))
+                .map_err(|e| ResourceError::IOError{err: e, file: src.into()});
+        }
+        if let Some(format) = self.unpack {
+            let file = fs::File::open(src).map_err(
+                |e| ResourceError::IOError{err: e, file: src.into()})?;
+            return self.unpack_into(format, file, build_dir);
+        }
+        fs::copy(src, target).map_err(
+            |e| ResourceError::IOError{err: e, file: src.into()})?;
+        Ok(())
+    }
+
+    // As install, but for a verified download in the build directory that
+    // nothing else needs, which is moved into place rather than copied
+    // unless it has to be unpacked.
+    fn install_part(&self, part: &Path, build_dir: &Path) -> Result <(), ResourceError> {
+        if self.unpack.is_some() && !part.is_dir() {
+            return self.install(part, build_dir);
+        }
+        let target = build_dir.join(self.name);
+        fs::rename(part, &target).map_err(
+            |e| ResourceError::IOError{err: e, file: target.clone()})?;
+        if target.is_dir() {
+            dirs::normalize_mtimes(&target).map_err(
+                |e| ResourceError::IOError{err: e, file: target.clone()})?;
+        }
+        Ok(())
+    }
+
+    /// Makes sure that the resource is in `cache_dir`, fetching it from its
+    /// URL if it isn't, and returns its path there. Files are named after
+    /// their hash, and only put in the cache once that has been checked, so
+    /// anything found in the cache is trusted as it is.
+    pub fn prefetch(
+        &self,
+        cache_dir: &Path,
+        opts: &FetchOpts
+    ) -> Result<PathBuf, ResourceError> {
+        let key = format!("{}-{:x}", self.hash.algo(), self.hash);
+        let cached = cache_dir.join(&key);
+        if cached.exists() {
+            return Ok(cached);
+        }
+        fs::create_dir_all(cache_dir).map_err(
+            |e| ResourceError::IOError{err: e, file: cache_dir.into()})?;
+        // The pid keeps concurrent builds from writing to the same file
+        let part = cache_dir.join(
+            format!(".{}.{}.part", key, std::process::id()));
+        let res = self.download(&part, opts)
+            .and_then(|_| fs::rename(&part, &cached).map_err(
+                |e| ResourceError::IOError{err: e, file: cached.clone()}));
+        if res.is_err() {
+            remove_part(&part);
+        }
+        res.map(|_| cached)
+    }
+
+    pub(crate) fn fetch_resource<P: AsRef<Path>>(
+        &self,
+        build_dir: P,
+        opts: &FetchOpts
+    ) -> Result <(), ResourceError> {
+        let build_dir = build_dir.as_ref();
+        if let Some(cache_dir) = &opts.cache_dir {
+            let cached = self.prefetch(cache_dir, opts)?;
+            return self.install(&cached, build_dir);
+        }
+        if let [url] = self.urls.as_slice() {
+            // Without a mode to set, a local file can be used where it is
+            let in_place = self.executable.is_none()
+                && !Path::new(url.path()).is_dir();
+            if url.scheme() == "file" && in_place {
+                let src_path = Path::new(url.path());
+                self.verify_file(src_path)?;
+                return self.install(src_path, build_dir);
+            }
+        }
+        let part = build_dir.join(format!(".{}.part", self.name));
+        let res = self.download(&part, opts)
+            .and_then(|_| self.install_part(&part, build_dir));
+        remove_part(&part);
+        res
+    }
+}
+
+// Cleans up after a download, which may have been a file or a directory.
+// Errors are ignored, since there is probably an error already being
+// returned that matters more.
+fn remove_part(part: &Path) {
+    match fs::symlink_metadata(part) {
+        Ok(meta) if meta.is_dir() => { let _ = fs::remove_dir_all(part); }
+        Ok(_) => { let _ = fs::remove_file(part); }
+        Err(_) => (),
+    }
+}
+
+// Whether rev is a full SHA-1 or SHA-256 git object id
+fn is_object_id(rev: &str) -> bool {
+    (rev.len() == 40 || rev.len() == 64) && rev.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Settings for how [Resource]s are fetched, which are not part of any
+/// recipe.
+#[derive(Clone, Default)]
+pub struct FetchOpts {
+    cache_dir: Option<PathBuf>,
+    progress: Option<Arc<ProgressFn>>,
+    mirrors: HashMap<String, Vec<Url>>,
+}
+
+/// Called as a download goes on, with the name of the resource, the number
+/// of bytes received so far, and the total if the server said what it is.
+pub type ProgressFn = dyn Fn(&str, u64, Option<u64>) + Send + Sync;
+
+impl fmt::Debug for FetchOpts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FetchOpts")
+         .field("cache_dir", &self.cache_dir)
+         .field("progress", &self.progress.is_some())
+         .field("mirrors", &self.mirrors)
+         .finish()
+    }
+}
+
+impl FetchOpts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keeps fetched resources in `dir`, so that they are only fetched once.
+    /// See [Resource::prefetch].
+    pub fn set_cache_dir(&mut self, dir: PathBuf) -> &mut Self {
+        self.cache_dir = Some(dir);
+        self
+    }
+
+    pub fn set_progress<F>(&mut self, progress: F) -> &mut Self
+        where F: Fn(&str, u64, Option<u64>) + Send + Sync + 'static
+    {
+        self.progress = Some(Arc::new(progress));
+        self
+    }
+
+    /// Adds `url` to the end of the mirror set called `name`.
+    pub fn add_mirror(&mut self, name: &str, mut url: Url) -> &mut Self {
+        // So that joining paths onto it doesn't drop its last component
+        if !url.path().ends_with('/') {
+            let path = format!("{}/", url.path());
+            url.set_path(&path);
+        }
+        self.mirrors.entry(name.to_string()).or_default().push(url);
+        self
+    }
+
+    /// Adds the mirror sets listed in `file`. Each line is the name of a
+    /// set and a URL, separated by whitespace, and a set's URLs are tried in
+    /// the order they are listed. Blank lines and lines starting with `#`
+    /// are skipped.
+    pub fn load_mirrors(&mut self, file: &Path) -> Result<&mut Self, ResourceError> {
+        let contents = fs::read_to_string(file).map_err(
+            |e| ResourceError::IOError{err: e, file: file.into()})?;
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let line_err = || ResourceError::MirrorsFileError{
+                file: file.into(),
+                line: i + 1
+            };
+            let mut parts = line.split_whitespace();
+            let (name, url) = match (parts.next(), parts.next(), parts.next()) {
+                (Some(name), Some(url), None) => (name, url),
+                _ => return Err(line_err()),
+            };
+            let url = Url::parse(url).map_err(|_| line_err())?;
+            self.add_mirror(name, url);
+        }
+        Ok(self)
+    }
+
+    // Returns None if url isn't a mirror URL, and the name of the mirror set
+    // if the set isn't known.
+    fn expand_mirror(&self, url: &Url) -> Option<Result<Vec<Url>, String>> {
+        if url.scheme() != "mirror" {
+            return None;
+        }
+        let name = url.host_str().unwrap_or("");
+        let path = url.path().trim_start_matches('/');
+        let bases = match self.mirrors.get(name) {
+            Some(bases) => bases,
+            None => return Some(Err(name.to_string())),
+        };
+        Some(Ok(bases.iter().filter_map(|base| base.join(path).ok()).collect()))
+    }
 }
 
 #[cfg(feature = "serde")]
@@ -156,10 +710,16 @@ pub mod url_serde {
     /// ```TOML
     /// url = "file://absolute/path/to/example.sh"
     /// ```
+    /// Relative URLs are resolved against whatever this holds when they are
+    /// deserialized, so it has to be set to the location of each recipe
+    /// before that recipe is deserialized. `yafpm-build` does so for the
+    /// recipe it is given and again for each dependency recipe it loads.
+    ///
     /// Note that there is no mutex or other type of protective wrapper
-    /// around this; it's just an option. `yafpm-build` is single-threaded and
-    /// hasn't needed such protections. But if your use case does, then please
-    /// file an issue.
+    /// around this; it's just an option. Writing it while another thread
+    /// deserializes a URL is a data race, so it may only be written and read
+    /// from one thread. `yafpm-build` is single-threaded and hasn't needed
+    /// more than that. But if your use case does, then please file an issue.
     pub static mut SERDE_BASE_URL: Option<Url> = None;
 
     struct UrlVisitor;
@@ -178,12 +738,13 @@ pub mod url_serde {
             let base_url;
             // I don't know any way to provide another argument to deserialize
             // functions, so a static is all I can think of to smuggle in
-            // a base url. Right now there are no mutexes or other protections,
-            // but it is modified by yafpm-build a maximum of one time before
-            // use, so I think it should be okay.
+            // a base url. Right now there are no mutexes or other protections.
+            // yafpm-build sets it again before each recipe it loads, but only
+            // ever from its one thread, and never while a recipe is being
+            // deserialized, so this read doesn't race with a write.
             unsafe {
                 let options = Url::options();
-                base_url = options.base_url(SERDE_BASE_URL.as_ref());
+                base_url = options.base_url((*std::ptr::addr_of!(SERDE_BASE_URL)).as_ref());
             }
 
             base_url.parse(s).map_err(|err| {
@@ -198,4 +759,325 @@ pub mod url_serde {
     ) -> Result<Url, D::Error> {
         deserializer.deserialize_str(UrlVisitor)
     }
+
+    /// The same as the parent module, but for one or more URLs, which may be
+    /// written as a single string or as a list.
+    pub mod one_or_many {
+        use std::fmt;
+        use serde::{ser,de};
+        use serde::ser::SerializeSeq;
+        use url::Url;
+
+        pub fn serialize<S: ser::Serializer>(
+            urls: &[Url],
+            serializer: S
+        ) -> Result<S::Ok, S::Error> {
+            if let [url] = urls {
+                return super::serialize(url, serializer);
+            }
+            let mut seq = serializer.serialize_seq(Some(urls.len()))?;
+            for url in urls {
+                seq.serialize_element(url.as_str())?;
+            }
+            seq.end()
+        }
+
+        struct UrlsVisitor;
+
+        impl<'de> de::Visitor<'de> for UrlsVisitor {
+            type Value = Vec<Url>;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("an URL or a non-empty list of URLs")
+            }
+
+            fn visit_str<E>(self, s: &str) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                de::Visitor::visit_str(super::UrlVisitor, s).map(|url| vec![url])
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+            where
+                A: de::SeqAccess<'de>,
+            {
+                let mut urls = Vec::new();
+                while let Some(s) = seq.next_element::<String>()? {
+                    urls.push(de::Visitor::visit_str(super::UrlVisitor, &s)?);
+                }
+                if urls.is_empty() {
+                    return Err(de::Error::invalid_length(0, &self));
+                }
+                Ok(urls)
+            }
+        }
+
+        pub fn deserialize<'de, D: de::Deserializer<'de>>(
+            deserializer: D
+        ) -> Result<Vec<Url>, D::Error> {
+            deserializer.deserialize_any(UrlsVisitor)
+        }
+    }
+
+    /// The same as the parent module, but for an optional URL.
+    pub mod opt {
+        use serde::{ser,de};
+        use url::Url;
+
+        pub fn serialize<S: ser::Serializer>(
+            url: &Option<Url>,
+            serializer: S
+        ) -> Result<S::Ok, S::Error> {
+            match url {
+                Some(url) => super::serialize(url, serializer),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: de::Deserializer<'de>>(
+            deserializer: D
+        ) -> Result<Option<Url>, D::Error> {
+            super::deserialize(deserializer).map(Some)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+    use blake2::{Blake2s, Digest};
+    use crate::hashes::HashAlgo;
+
+    #[test]
+    fn test_prefetch() {
+        let tmp = env::temp_dir().join(format!("yafpm-prefetch-test-{}", process::id()));
+        fs::create_dir_all(&tmp).unwrap();
+        let src = tmp.join("src.txt");
+        fs::write(&src, b"resource contents").unwrap();
+        let resource = Resource::new(
+            "src.txt",
+            Blake2s::digest(b"resource contents").into(),
+            Url::from_file_path(&src).unwrap()
+        );
+
+        let cache_dir = tmp.join("cache");
+        let cached = resource.prefetch(&cache_dir, &FetchOpts::new()).unwrap();
+        assert_eq!(fs::read(&cached).unwrap(), b"resource contents");
+        // Once it's cached, the original isn't needed anymore
+        fs::remove_file(&src).unwrap();
+        assert_eq!(resource.prefetch(&cache_dir, &FetchOpts::new()).unwrap(), cached);
+        fs::remove_dir_all(tmp).unwrap();
+    }
+
+    // Serves body to each of the next `count` connections
+    #[cfg(feature = "minreq")]
+    fn serve(status: &'static str, body: &'static [u8], count: usize) -> Url {
+        use std::io::{BufRead, Write};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().take(count) {
+                let mut stream = stream.unwrap();
+                let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+                write!(stream, "HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n",
+                       status, body.len()).unwrap();
+                stream.write_all(body).unwrap();
+            }
+        });
+        Url::parse(&format!("http://{}/src.txt", addr)).unwrap()
+    }
+
+    #[cfg(feature = "minreq")]
+    #[test]
+    fn test_fetch_http() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        let body: &[u8] = &[7; 100_000];
+        let url = serve("200 OK", body, 3);
+        let cache_dir = env::temp_dir().join(format!("yafpm-fetch-http-test-{}", process::id()));
+        let received = Arc::new(AtomicU64::new(0));
+        let mut opts = FetchOpts::new();
+        let received_clone = received.clone();
+        opts.set_progress(move |_, n, total| {
+            assert_eq!(total, Some(100_000));
+            received_clone.store(n, Ordering::SeqCst);
+        });
+
+        let bad = Resource::new("src.txt", Blake2s::digest(b"").into(), url.clone());
+        assert!(matches!(bad.prefetch(&cache_dir, &opts),
+                         Err(ResourceError::HashError{..})));
+        // Nothing is left behind by the failed download
+        assert_eq!(fs::read_dir(&cache_dir).unwrap().count(), 0);
+
+        let good = Resource::new("src.txt", Blake2s::digest(body).into(), url);
+        let cached = good.prefetch(&cache_dir, &opts).unwrap();
+        assert_eq!(fs::read(cached).unwrap(), body);
+        assert_eq!(received.load(Ordering::SeqCst), 100_000);
+
+        // Without a cache, the download is moved into the build directory
+        let build_dir = cache_dir.join("build");
+        fs::create_dir(&build_dir).unwrap();
+        good.fetch_resource(&build_dir, &opts).unwrap();
+        assert_eq!(fs::read(build_dir.join("src.txt")).unwrap(), body);
+        assert_eq!(fs::read_dir(&build_dir).unwrap().count(), 1);
+        fs::remove_dir_all(cache_dir).unwrap();
+    }
+
+    #[cfg(feature = "minreq")]
+    #[test]
+    fn test_fetch_fallback() {
+        let body: &[u8] = b"resource contents";
+        let down = serve("503 Service Unavailable", b"", 3);
+        let up = serve("200 OK", body, 2);
+        let cache_dir = env::temp_dir().join(format!("yafpm-fetch-fallback-test-{}", process::id()));
+        let mut opts = FetchOpts::new();
+        opts.add_mirror("test", down.join("/mirror").unwrap());
+        opts.add_mirror("test", up.join("/mirror").unwrap());
+
+        let mut resource = Resource::new("src.txt", Blake2s::digest(body).into(), down.clone());
+        resource.add_urls(vec![up.clone()]);
+        let cached = resource.prefetch(&cache_dir, &opts).unwrap();
+        fs::remove_file(cached).unwrap();
+
+        let mirror_url = Url::parse("mirror://test/src.txt").unwrap();
+        let resource = Resource::new("src.txt", Blake2s::digest(body).into(), mirror_url);
+        let cached = resource.prefetch(&cache_dir, &opts).unwrap();
+        assert_eq!(fs::read(&cached).unwrap(), body);
+        fs::remove_file(cached).unwrap();
+
+        let mut resource = Resource::new("src.txt", Blake2s::digest(body).into(), down);
+        resource.add_urls(vec![Url::parse("mirror://nonexistent/src.txt").unwrap()]);
+        match resource.prefetch(&cache_dir, &opts) {
+            Err(ResourceError::AllURLsFailed{attempts, ..}) => assert_eq!(attempts.len(), 2),
+            _ => panic!("expected every URL to fail"),
+        }
+        fs::remove_dir_all(cache_dir).unwrap();
+    }
+
+    fn git(dir: &Path, args: &[&str]) -> String {
+        let out = std::process::Command::new("git")
+            .current_dir(dir)
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(out.status.success());
+        String::from_utf8(out.stdout).unwrap().trim().to_string()
+    }
+
+    #[test]
+    fn test_fetch_git() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = env::temp_dir().join(format!("yafpm-fetch-git-test-{}", process::id()));
+        let work = tmp.join("work");
+        fs::create_dir_all(work.join("src")).unwrap();
+        git(&work, &["init", "--quiet"]);
+        fs::write(work.join("src/main.c"), b"int main() { return 0; }\n").unwrap();
+        fs::write(work.join("build.sh"), b"#!/bin/sh\n").unwrap();
+        fs::set_permissions(work.join("build.sh"), fs::Permissions::from_mode(0o755)).unwrap();
+        git(&work, &["add", "."]);
+        git(&work, &["commit", "--quiet", "-m", "first"]);
+        let rev = git(&work, &["rev-parse", "HEAD"]);
+        // A later commit, which mustn't end up in the checkout
+        fs::write(work.join("later"), b"later").unwrap();
+        git(&work, &["add", "."]);
+        git(&work, &["commit", "--quiet", "-m", "second"]);
+        git(&tmp, &["clone", "--quiet", "--bare", "work", "repo.git"]);
+
+        // The tree at rev, without the .git directory
+        let mut hasher = HashAlgo::Sha256.hasher();
+        fs::remove_file(work.join("later")).unwrap();
+        fs::remove_dir_all(work.join(".git")).unwrap();
+        walk_dir::write_archive(&work, &mut hasher).unwrap();
+        let url = Url::parse(&format!("git+file://{}?rev={}",
+                                      tmp.join("repo.git").display(), rev)).unwrap();
+        let resource = Resource::new("src", hasher.finish(), url);
+
+        let build_dir = tmp.join("build");
+        fs::create_dir(&build_dir).unwrap();
+        for bad_rev in ["HEAD", "--help", &rev[..12]] {
+            let url = Url::parse(&format!("git+file://{}?rev={}",
+                                          tmp.join("repo.git").display(), bad_rev)).unwrap();
+            let bad = Resource::new("src", resource.hash.clone(), url);
+            assert!(matches!(bad.fetch_resource(&build_dir, &FetchOpts::new()),
+                             Err(ResourceError::GitRevError{..})));
+        }
+        resource.fetch_resource(&build_dir, &FetchOpts::new()).unwrap();
+        assert!(build_dir.join("src/src/main.c").exists());
+        assert!(!build_dir.join("src/later").exists());
+        assert!(!build_dir.join("src/.git").exists());
+        fs::remove_dir_all(tmp).unwrap();
+    }
+
+    #[test]
+    fn test_fetch_dir() {
+        let tmp = env::temp_dir().join(format!("yafpm-fetch-dir-test-{}", process::id()));
+        let src = tmp.join("checkout");
+        for dir in &["src", "target/debug", ".git", "doc/target"] {
+            fs::create_dir_all(src.join(dir)).unwrap();
+        }
+        for file in &["src/main.c", "src/main.o", "target/debug/main", ".git/HEAD", "doc/target/x"] {
+            fs::write(src.join(file), file.as_bytes()).unwrap();
+        }
+
+        let expected = tmp.join("expected");
+        fs::create_dir_all(expected.join("src")).unwrap();
+        fs::write(expected.join("src/main.c"), b"src/main.c").unwrap();
+        fs::create_dir(expected.join("doc")).unwrap();
+        let mut hasher = HashAlgo::Blake2s.hasher();
+        walk_dir::write_archive(&expected, &mut hasher).unwrap();
+
+        let mut resource = Resource::new(
+            "checkout",
+            hasher.finish(),
+            Url::from_file_path(&src).unwrap()
+        );
+        resource.add_excludes(vec!["/target/", ".git/", "*.o", "doc/target"]);
+        let build_dir = tmp.join("build");
+        fs::create_dir(&build_dir).unwrap();
+        resource.fetch_resource(&build_dir, &FetchOpts::new()).unwrap();
+        assert!(build_dir.join("checkout/src/main.c").exists());
+        assert!(!build_dir.join("checkout/target").exists());
+        fs::remove_dir_all(tmp).unwrap();
+    }
+
+    #[test]
+    fn test_fetch_executable() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = env::temp_dir().join(format!("yafpm-fetch-executable-test-{}", process::id()));
+        let build_dir = tmp.join("build");
+        fs::create_dir_all(&build_dir).unwrap();
+        let src = tmp.join("tool");
+        fs::write(&src, b"#!/bin/sh\n").unwrap();
+        fs::set_permissions(&src, fs::Permissions::from_mode(0o755)).unwrap();
+        let mut hasher = HashAlgo::Blake2s.hasher();
+        walk_dir::write_archive(&src, &mut hasher).unwrap();
+        let exec_hash = hasher.finish();
+        fs::set_permissions(&src, fs::Permissions::from_mode(0o600)).unwrap();
+        let url = Url::from_file_path(&src).unwrap();
+
+        let mut resource = Resource::new("tool", exec_hash.clone(), url.clone());
+        resource.set_executable(true);
+        resource.fetch_resource(&build_dir, &FetchOpts::new()).unwrap();
+        let mode = fs::metadata(build_dir.join("tool")).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o755);
+
+        // The same hash doesn't do for the file without its executable bit
+        let mut resource = Resource::new("tool2", exec_hash, url);
+        resource.set_executable(false);
+        let res = resource.fetch_resource(&build_dir, &FetchOpts::new());
+        assert!(matches!(res, Err(ResourceError::HashError{..})));
+        fs::remove_dir_all(tmp).unwrap();
+    }
 }
@@ -0,0 +1,643 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+// 
+// Copyright (C) 2021 John Arnold
+//
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! Bookkeeping for the package store directory. Besides the package
+//! directories themselves, the store holds a few entries whose names start
+//! with a `.`, which are never treated as packages:
+//!
+//! * `.yafpm-db/` has one record per installed package, named after its
+//!   `pkg_ident`. A record is a text file with one `key value` pair per
+//!   line, giving the package's name, version, hash, registration time,
+//!   recipe, runtime dependencies (`dep`), the packages its output was
+//!   found to refer to (`ref`) and build settings (`setting`). While a
+//!   package is being built, `.yafpm-db/.<pkg_ident>.building` is locked by
+//!   the build.
+//! * `.gcroots/` is the default place for garbage collector roots, which are
+//!   symlinks to the packages that should be kept.
+
+use std::fs;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Component, Path, PathBuf};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use nix::errno::Errno;
+use nix::fcntl::{flock, FlockArg};
+use nix::libc;
+
+use crate::dirs;
+use crate::package::Package as PKG;
+use crate::package::MAIN_OUTPUT;
+
+pub const DB_DIR: &str = ".yafpm-db";
+pub const GC_ROOTS_DIR: &str = ".gcroots";
+
+#[derive(Debug, thiserror::Error)]
+pub enum StoreError {
+    #[error("IO error while accessing {}", .file.display())]
+    IOError {
+        #[source]
+        err: io::Error,
+        file: PathBuf
+    },
+    #[error("Malformed record for {ident} on line {line}")]
+    RecordError {
+        ident: String,
+        line: usize,
+    },
+}
+
+fn io_err(file: &Path) -> impl FnOnce(io::Error) -> StoreError + '_ {
+    move |err| StoreError::IOError{err, file: file.to_path_buf()}
+}
+
+fn record_path(pkg_store_dir: &Path, ident: &str) -> PathBuf {
+    let mut path = pkg_store_dir.join(DB_DIR);
+    path.push(ident);
+    path
+}
+
+// The file that marks `ident` as being built
+fn building_path(pkg_store_dir: &Path, ident: &str) -> PathBuf {
+    let mut path = pkg_store_dir.join(DB_DIR);
+    path.push(format!(".{}.building", ident));
+    path
+}
+
+fn nix_to_io(err: nix::Error) -> io::Error {
+    match err.as_errno() {
+        Some(errno) => io::Error::from_raw_os_error(errno as i32),
+        None => io::Error::other(err),
+    }
+}
+
+/// Marks packages as being built until it is dropped, so that
+/// [collect_garbage] leaves their directories alone before they are
+/// registered. Each mark is a file in `.yafpm-db` that is locked for as long
+/// as this is held, so the mark of a build that died goes stale by itself.
+pub(crate) struct BuildLock {
+    paths: Vec<PathBuf>,
+    // Only held for their locks, which go with them
+    _files: Vec<fs::File>,
+}
+
+impl BuildLock {
+    /// Marks each of `idents` as being built, waiting for any other build
+    /// of them to finish first.
+    pub(crate) fn acquire<I>(pkg_store_dir: &Path, idents: I) -> Result<Self, StoreError>
+        where I: IntoIterator<Item = String>
+    {
+        let db_dir = pkg_store_dir.join(DB_DIR);
+        fs::create_dir_all(&db_dir).map_err(io_err(&db_dir))?;
+        let mut lock = BuildLock{paths: Vec::new(), _files: Vec::new()};
+        for ident in idents {
+            let path = building_path(pkg_store_dir, &ident);
+            let file = lock_file(&path).map_err(io_err(&path))?;
+            lock.paths.push(path);
+            lock._files.push(file);
+        }
+        Ok(lock)
+    }
+}
+
+impl Drop for BuildLock {
+    fn drop(&mut self) {
+        // Removed while still locked, so that nobody finds it stale first
+        for path in &self.paths {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+// Opens and locks the file at `path`, creating it if need be. If whoever
+// held the lock before removed the file meanwhile, the lock is on a file that
+// marks nothing any more, so it's taken again on a new one.
+fn lock_file(path: &Path) -> Result<fs::File, io::Error> {
+    loop {
+        let file = fs::OpenOptions::new().write(true).create(true).truncate(false).open(path)?;
+        flock(file.as_raw_fd(), FlockArg::LockExclusive).map_err(nix_to_io)?;
+        let locked = file.metadata()?;
+        match fs::metadata(path) {
+            Ok(meta) if (meta.dev(), meta.ino()) == (locked.dev(), locked.ino()) => {
+                return Ok(file);
+            }
+            Ok(_) => continue,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Whether a build that is still running has marked `ident` as being built
+fn being_built(pkg_store_dir: &Path, ident: &str) -> Result<bool, StoreError> {
+    let path = building_path(pkg_store_dir, ident);
+    let file = match fs::File::open(&path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(io_err(&path)(e)),
+    };
+    match flock(file.as_raw_fd(), FlockArg::LockSharedNonblock) {
+        Ok(()) => Ok(false),
+        Err(nix::Error::Sys(Errno::EAGAIN)) => Ok(true),
+        Err(e) => Err(io_err(&path)(nix_to_io(e))),
+    }
+}
+
+/// What the store database knows about an installed package.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PkgRecord {
+    pub ident: String,
+    pub name: String,
+    pub version: String,
+    /// The output hash, as it would be written in a recipe.
+    pub hash: String,
+    /// The scheme used to serialize the output for `hash`.
+    pub hash_version: u8,
+    /// The identifiers of the packages this one depends on at runtime.
+    pub deps: Vec<String>,
+    /// The identifiers of the packages that the output was found to refer
+    /// to when it was built, other than itself.
+    pub refs: Vec<String>,
+    pub build_settings: Vec<(String, String)>,
+    /// When the package was registered, in seconds since the Unix epoch.
+    pub registered: u64,
+    /// The recipe that the package was built from, if it is known.
+    pub recipe: Option<String>,
+}
+
+// Record values are one per line, so newlines (and the backslashes used to
+// escape them) have to be escaped.
+fn escape(val: &str) -> String {
+    val.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape(val: &str) -> String {
+    let mut out = String::with_capacity(val.len());
+    let mut chars = val.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+impl PkgRecord {
+    // The record of `output` of `pkg`, which must be one of its outputs
+    fn from_pkg(pkg: &PKG, output: &str, refs: Vec<String>, registered: u64) -> Self {
+        let mut build_settings: Vec<_> = pkg.build_settings.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        build_settings.sort();
+        let mut deps: Vec<String> = pkg.deps.iter().map(PKG::pkg_ident).collect();
+        let name = if output == MAIN_OUTPUT {
+            pkg.pkg_name.to_string()
+        } else {
+            // The other outputs are meant to go with the main one, as headers
+            // go with their library, and may well refer to it
+            deps.push(pkg.pkg_ident());
+            format!("{}.{}", pkg.pkg_name, output)
+        };
+        PkgRecord {
+            // Unwraps are fine, output is one of pkg's
+            ident: pkg.output_ident(output).unwrap(),
+            name,
+            version: pkg.pkg_version.to_string(),
+            hash: pkg.output_hash(output).unwrap().to_string(),
+            hash_version: pkg.hash_version.into(),
+            deps,
+            refs,
+            build_settings,
+            registered,
+            recipe: pkg.recipe.as_ref().map(|u| u.to_string()),
+        }
+    }
+
+    fn write_to<W: Write>(&self, w: &mut W) -> Result<(), io::Error> {
+        writeln!(w, "name {}", escape(&self.name))?;
+        writeln!(w, "version {}", escape(&self.version))?;
+        writeln!(w, "hash {}", self.hash)?;
+        writeln!(w, "hash_version {}", self.hash_version)?;
+        writeln!(w, "registered {}", self.registered)?;
+        if let Some(recipe) = &self.recipe {
+            writeln!(w, "recipe {}", escape(recipe))?;
+        }
+        for dep in &self.deps {
+            writeln!(w, "dep {}", dep)?;
+        }
+        for r in &self.refs {
+            writeln!(w, "ref {}", r)?;
+        }
+        for (k, v) in &self.build_settings {
+            writeln!(w, "setting {}={}", escape(k), escape(v))?;
+        }
+        Ok(())
+    }
+
+    fn read_from<R: BufRead>(ident: &str, r: R) -> Result<Self, StoreError> {
+        let mut record = PkgRecord {
+            ident: ident.to_string(),
+            ..Default::default()
+        };
+        let bad_line = |n: usize| StoreError::RecordError{
+            ident: ident.to_string(),
+            line: n + 1
+        };
+        for (n, line) in r.lines().enumerate() {
+            let line = line.map_err(io_err(Path::new(ident)))?;
+            let (key, val) = line.split_once(' ').ok_or_else(|| bad_line(n))?;
+            match key {
+                "name" => record.name = unescape(val),
+                "version" => record.version = unescape(val),
+                "hash" => record.hash = val.to_string(),
+                "hash_version" => {
+                    record.hash_version = val.parse().map_err(|_| bad_line(n))?;
+                }
+                "registered" => {
+                    record.registered = val.parse().map_err(|_| bad_line(n))?;
+                }
+                "recipe" => record.recipe = Some(unescape(val)),
+                "dep" => record.deps.push(val.to_string()),
+                "ref" => record.refs.push(val.to_string()),
+                "setting" => {
+                    let (k, v) = val.split_once('=').ok_or_else(|| bad_line(n))?;
+                    record.build_settings.push((unescape(k), unescape(v)));
+                }
+                // Leave room for newer versions to add keys
+                _ => {}
+            }
+        }
+        Ok(record)
+    }
+}
+
+/// Records `pkg` in the store database, with a record for each of its
+/// outputs, along with the references found in that output in `refs`, keyed
+/// by output name. Each record is written to a temporary file and then
+/// renamed into place, so readers either see the whole record or none of it.
+/// An output that is already registered keeps the time it was first
+/// registered at.
+pub fn register(
+    pkg_store_dir: &Path,
+    pkg: &PKG,
+    refs: &HashMap<&str, Vec<String>>
+) -> Result<(), StoreError> {
+    use std::time::SystemTime;
+
+    let db_dir = pkg_store_dir.join(DB_DIR);
+    fs::create_dir_all(&db_dir).map_err(io_err(&db_dir))?;
+    let registered = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_else(|e| e.duration()).as_secs();
+    for output in pkg.output_names() {
+        let output_refs = refs.get(output).cloned().unwrap_or_default();
+        let mut record = PkgRecord::from_pkg(pkg, output, output_refs, registered);
+        if let Some(old) = read_record(pkg_store_dir, &record.ident)? {
+            record.registered = old.registered;
+        }
+        let path = record_path(pkg_store_dir, &record.ident);
+        let tmp_path = db_dir.join(format!(".{}.tmp", record.ident));
+        let mut tmp = fs::File::create(&tmp_path).map_err(io_err(&tmp_path))?;
+        record.write_to(&mut tmp).map_err(io_err(&tmp_path))?;
+        tmp.sync_all().map_err(io_err(&tmp_path))?;
+        fs::rename(&tmp_path, &path).map_err(io_err(&path))?;
+    }
+    Ok(())
+}
+
+/// Looks up the record of an installed package, if there is one.
+pub fn read_record(
+    pkg_store_dir: &Path,
+    ident: &str
+) -> Result<Option<PkgRecord>, StoreError> {
+    let path = record_path(pkg_store_dir, ident);
+    let file = match fs::File::open(&path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(io_err(&path)(e)),
+    };
+    PkgRecord::read_from(ident, BufReader::new(file)).map(Some)
+}
+
+/// Reads every record in the store database.
+pub fn read_all_records(pkg_store_dir: &Path) -> Result<Vec<PkgRecord>, StoreError> {
+    let db_dir = pkg_store_dir.join(DB_DIR);
+    let entries = match fs::read_dir(&db_dir) {
+        Ok(e) => e,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(io_err(&db_dir)(e)),
+    };
+    let mut records = Vec::new();
+    for entry in entries {
+        let name = entry.map_err(io_err(&db_dir))?.file_name();
+        // Skips any temporary files left by register
+        if name.as_bytes().starts_with(b".") {
+            continue;
+        }
+        let ident = name.to_string_lossy();
+        if let Some(record) = read_record(pkg_store_dir, &ident)? {
+            records.push(record);
+        }
+    }
+    records.sort_by(|x, y| x.ident.cmp(&y.ident));
+    Ok(records)
+}
+
+/// Reads back the runtime dependencies of a package, both those declared and
+/// those its output was found to refer to. A package without a record (e.g.
+/// one installed by hand) is taken to have no dependencies.
+pub fn read_refs(pkg_store_dir: &Path, ident: &str) -> Result<Vec<String>, StoreError> {
+    Ok(read_record(pkg_store_dir, ident)?
+        .map(|r| r.deps.into_iter().chain(r.refs).collect())
+        .unwrap_or_default())
+}
+
+/// Finds the installed packages that depend on `ident` at runtime, or were
+/// found to refer to it.
+pub fn reverse_deps(pkg_store_dir: &Path, ident: &str) -> Result<Vec<String>, StoreError> {
+    Ok(read_all_records(pkg_store_dir)?.into_iter()
+        .filter(|r| r.deps.iter().chain(&r.refs).any(|d| d == ident))
+        .map(|r| r.ident)
+        .collect())
+}
+
+// Whether a store entry is named like Package::pkg_ident() would name it.
+// Nothing stops the store from being a directory like /tmp, and the garbage
+// collector had better leave alone anything that isn't a package.
+fn looks_like_ident(name: &[u8]) -> bool {
+    let mut parts = name.rsplitn(3, |b| *b == b'-');
+    let hash = parts.next().unwrap_or_default();
+    parts.count() == 2
+        && hash.len() >= 26
+        && hash.iter().all(|b| matches!(b, b'A'..=b'Z' | b'2'..=b'7'))
+}
+
+/// Lists the package identifiers in the store, skipping the `.` entries and
+/// anything else that isn't a package directory.
+pub fn installed_idents(pkg_store_dir: &Path) -> Result<Vec<String>, StoreError> {
+    let mut idents = Vec::new();
+    for entry in fs::read_dir(pkg_store_dir).map_err(io_err(pkg_store_dir))? {
+        let entry = entry.map_err(io_err(pkg_store_dir))?;
+        let name = entry.file_name();
+        let is_dir = entry.file_type().map_err(io_err(&entry.path()))?.is_dir();
+        if !is_dir || !looks_like_ident(name.as_bytes()) {
+            continue;
+        }
+        idents.push(name.to_string_lossy().into_owned());
+    }
+    idents.sort();
+    Ok(idents)
+}
+
+/// Finds the packages that the symlinks in `roots_dir` point into. Dangling
+/// symlinks are skipped.
+pub fn read_gc_roots(
+    pkg_store_dir: &Path,
+    roots_dir: &Path
+) -> Result<Vec<String>, StoreError> {
+    let entries = match fs::read_dir(roots_dir) {
+        Ok(e) => e,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(io_err(roots_dir)(e)),
+    };
+    // Both sides are compared canonically, so that neither a relative store
+    // directory nor a relative link like ../foo-1.0-HASH hides a root
+    let store = fs::canonicalize(pkg_store_dir).map_err(io_err(pkg_store_dir))?;
+    let mut roots = Vec::new();
+    for entry in entries {
+        let link = entry.map_err(io_err(roots_dir))?.path();
+        match fs::read_link(&link) {
+            Ok(_) => (),
+            // Anything that isn't a symlink isn't a root
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => continue,
+            Err(e) => return Err(io_err(&link)(e)),
+        }
+        let target = match fs::canonicalize(&link) {
+            Ok(t) => t,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(io_err(&link)(e)),
+        };
+        // The root may point anywhere inside a package, so only the first
+        // component below the store directory matters.
+        if let Ok(rest) = target.strip_prefix(&store) {
+            if let Some(Component::Normal(ident)) = rest.components().next() {
+                roots.push(ident.to_string_lossy().into_owned());
+            }
+        }
+    }
+    Ok(roots)
+}
+
+/// Returns every package reachable from `roots` through recorded runtime
+/// dependencies, including the roots themselves.
+pub fn runtime_closure<I>(
+    pkg_store_dir: &Path,
+    roots: I
+) -> Result<HashSet<String>, StoreError>
+    where I: IntoIterator<Item = String>
+{
+    let mut live = HashSet::new();
+    let mut queue: VecDeque<String> = roots.into_iter().collect();
+    while let Some(ident) = queue.pop_front() {
+        if live.contains(&ident) {
+            continue;
+        }
+        queue.extend(read_refs(pkg_store_dir, &ident)?);
+        live.insert(ident);
+    }
+    Ok(live)
+}
+
+/// Deletes every package in `pkg_store_dir` that isn't in the runtime closure
+/// of the GC roots in `roots_dir`, and returns the identifiers of what was
+/// (or with `dry_run`, what would have been) deleted. Package directories
+/// without a record are deleted too, such as those left by a build that
+/// died, unless a build that is still running has marked them with a
+/// [BuildLock].
+pub fn collect_garbage(
+    pkg_store_dir: &Path,
+    roots_dir: &Path,
+    dry_run: bool
+) -> Result<Vec<String>, StoreError> {
+    let roots = read_gc_roots(pkg_store_dir, roots_dir)?;
+    let live = runtime_closure(pkg_store_dir, roots)?;
+    let mut dead = Vec::new();
+    for ident in installed_idents(pkg_store_dir)? {
+        if !live.contains(&ident) && !being_built(pkg_store_dir, &ident)? {
+            dead.push(ident);
+        }
+    }
+    if dry_run {
+        return Ok(dead);
+    }
+    for ident in &dead {
+        let path = pkg_store_dir.join(ident);
+        dirs::set_writable_dirs(&path).map_err(io_err(&path))?;
+        fs::remove_dir_all(&path).map_err(io_err(&path))?;
+        // Along with the stale mark of a build that died, if there is one
+        for file in [record_path(pkg_store_dir, ident), building_path(pkg_store_dir, ident)] {
+            match fs::remove_file(&file) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound =>
+                    return Err(io_err(&file)(e)),
+                _ => {}
+            }
+        }
+    }
+    Ok(dead)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+    use std::os::unix::fs::symlink;
+    use blake2::{Blake2s, Digest};
+
+    fn example_pkg(name: &'static str) -> PKG<'static> {
+        PKG::new(name, "1.0.0", Blake2s::digest(name.as_bytes()).into())
+    }
+
+    #[test]
+    fn test_collect_garbage() {
+        let store = env::temp_dir().join(format!("yafpm-gc-test-{}", process::id()));
+        fs::create_dir(&store).unwrap();
+        let dep = example_pkg("dep");
+        let unused = example_pkg("unused");
+        let found = example_pkg("found");
+        let mut top = example_pkg("top");
+        top.add_deps(Some(example_pkg("dep")));
+        for pkg in [&dep, &unused, &found, &top] {
+            let out = store.join(pkg.pkg_ident());
+            fs::create_dir(&out).unwrap();
+            fs::write(out.join("file"), b"contents").unwrap();
+            dirs::set_readonly_all(&out, true).unwrap();
+            register(&store, pkg, &HashMap::new()).unwrap();
+        }
+        // Only found to be referred to, rather than declared
+        let refs = HashMap::from([(MAIN_OUTPUT, vec![found.pkg_ident()])]);
+        register(&store, &top, &refs).unwrap();
+        let roots = store.join(GC_ROOTS_DIR);
+        fs::create_dir(&roots).unwrap();
+        symlink(store.join(top.pkg_ident()).join("file"), roots.join("top")).unwrap();
+
+        let deleted = collect_garbage(&store, &roots, false).unwrap();
+        assert_eq!(deleted, vec![unused.pkg_ident()]);
+        assert!(!store.join(unused.pkg_ident()).exists());
+        assert!(store.join(dep.pkg_ident()).exists());
+        assert!(store.join(found.pkg_ident()).exists());
+        assert!(store.join(top.pkg_ident()).exists());
+
+        fs::remove_file(roots.join("top")).unwrap();
+        collect_garbage(&store, &roots, false).unwrap();
+        assert_eq!(installed_idents(&store).unwrap().len(), 0);
+        fs::remove_dir_all(store).unwrap();
+    }
+
+    #[test]
+    fn test_relative_gc_root() {
+        let store = env::temp_dir().join(format!("yafpm-gc-rel-test-{}", process::id()));
+        fs::create_dir(&store).unwrap();
+        let kept = example_pkg("kept");
+        let building = example_pkg("building");
+        let died = example_pkg("died");
+        for pkg in [&kept, &building, &died] {
+            fs::create_dir(store.join(pkg.pkg_ident())).unwrap();
+        }
+        let lock = BuildLock::acquire(&store, Some(building.pkg_ident())).unwrap();
+        // As a build that died would leave it
+        fs::write(building_path(&store, &died.pkg_ident()), b"").unwrap();
+        register(&store, &kept, &HashMap::new()).unwrap();
+        let roots = store.join(GC_ROOTS_DIR);
+        fs::create_dir(&roots).unwrap();
+        symlink(Path::new("..").join(kept.pkg_ident()), roots.join("kept")).unwrap();
+        symlink("../gone-1.0-AAAAAAAAAAAAAAAAAAAAAAAAAA", roots.join("dangling")).unwrap();
+        fs::write(roots.join("not-a-link"), b"").unwrap();
+
+        assert_eq!(read_gc_roots(&store, &roots).unwrap(), vec![kept.pkg_ident()]);
+        // Neither `building` nor `died` has a record, but only `building` is
+        // still being built
+        let deleted = collect_garbage(&store, &roots, false).unwrap();
+        assert_eq!(deleted, vec![died.pkg_ident()]);
+        assert!(!building_path(&store, &died.pkg_ident()).exists());
+        fs::remove_file(roots.join("kept")).unwrap();
+        let deleted = collect_garbage(&store, &roots, false).unwrap();
+        assert_eq!(deleted, vec![kept.pkg_ident()]);
+        assert!(store.join(building.pkg_ident()).exists());
+        drop(lock);
+        assert!(!building_path(&store, &building.pkg_ident()).exists());
+        let deleted = collect_garbage(&store, &roots, false).unwrap();
+        assert_eq!(deleted, vec![building.pkg_ident()]);
+        fs::remove_dir_all(store).unwrap();
+    }
+
+    #[test]
+    fn test_record_round_trip() {
+        let mut pkg = example_pkg("pkg");
+        pkg.add_deps(Some(example_pkg("dep")));
+        pkg.add_build_settings(Some(("CFLAGS", "-O2\\\n-g")));
+        let refs = vec![example_pkg("ref").pkg_ident()];
+        let record = PkgRecord::from_pkg(&pkg, MAIN_OUTPUT, refs, 1637452800);
+        let mut buf = Vec::new();
+        record.write_to(&mut buf).unwrap();
+        let read = PkgRecord::read_from(&record.ident, &buf[..]).unwrap();
+        assert_eq!(record, read);
+        assert_eq!(read.build_settings[0].1, "-O2\\\n-g");
+    }
+
+    #[test]
+    fn test_register_again() {
+        let store = env::temp_dir().join(format!("yafpm-register-test-{}", process::id()));
+        let pkg = example_pkg("pkg");
+        let mut record = PkgRecord::from_pkg(&pkg, MAIN_OUTPUT, Vec::new(), 1637452800);
+        fs::create_dir_all(store.join(DB_DIR)).unwrap();
+        let mut file = fs::File::create(record_path(&store, &record.ident)).unwrap();
+        record.write_to(&mut file).unwrap();
+        record.refs.push(example_pkg("ref").pkg_ident());
+        let refs = HashMap::from([(MAIN_OUTPUT, record.refs.clone())]);
+        register(&store, &pkg, &refs).unwrap();
+        assert_eq!(read_record(&store, &record.ident).unwrap(), Some(record));
+        fs::remove_dir_all(store).unwrap();
+    }
+
+    #[test]
+    fn test_output_record() {
+        use crate::package::Output;
+        let mut pkg = example_pkg("pkg");
+        pkg.add_deps(Some(example_pkg("dep")));
+        pkg.add_outputs(Some(Output::new("dev", Blake2s::digest(b"dev").into())));
+        let record = PkgRecord::from_pkg(&pkg, "dev", Vec::new(), 1637452800);
+        assert_eq!(record.name, "pkg.dev");
+        assert_eq!(Some(record.ident), pkg.output_ident("dev"));
+        assert_eq!(record.deps, [example_pkg("dep").pkg_ident(), pkg.pkg_ident()]);
+        assert!(looks_like_ident(pkg.output_ident("dev").unwrap().as_bytes()));
+    }
+
+    #[test]
+    fn test_looks_like_ident() {
+        assert!(looks_like_ident(example_pkg("name").pkg_ident().as_bytes()));
+        assert!(!looks_like_ident(b"hsperfdata_root"));
+        assert!(!looks_like_ident(b"name-build-1637452800"));
+    }
+}
@@ -0,0 +1,314 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+// 
+// Copyright (C) 2021 John Arnold
+//
+// This program is free software; you can redistribute it and/or
+// modify it under the terms of the GNU General Public License
+// as published by the Free Software Foundation; either version 2
+// of the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use std::fs;
+use std::io;
+use std::io::{Read, Seek};
+use std::path::{Component, Path, PathBuf};
+use std::os::unix::fs::{symlink, OpenOptionsExt};
+
+use crate::dirs;
+
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
+
+/// The kinds of archive that a [Resource](crate::Resource) can be unpacked
+/// from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ArchiveFormat {
+    #[cfg_attr(feature = "serde", serde(rename = "tar"))]
+    Tar,
+    #[cfg_attr(feature = "serde", serde(rename = "tar.gz"))]
+    TarGz,
+    #[cfg_attr(feature = "serde", serde(rename = "tar.xz"))]
+    TarXz,
+    #[cfg_attr(feature = "serde", serde(rename = "zip"))]
+    Zip,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum UnpackError {
+    #[error("IO error while unpacking {}", .file.display())]
+    IOError {
+        #[source]
+        err: io::Error,
+        file: PathBuf
+    },
+    #[error("Archive entry {} points outside of the unpack directory", .0.display())]
+    UnsafePathError(PathBuf),
+    #[error("Archive entry {} is not a file, directory or link", .0.display())]
+    EntryTypeError(PathBuf),
+    #[error("Error while reading zip archive")]
+    ZipError(#[source] zip::result::ZipError),
+}
+
+fn io_err(file: &Path) -> impl FnOnce(io::Error) -> UnpackError + '_ {
+    move |err| UnpackError::IOError{err, file: file.to_path_buf()}
+}
+
+/// Extracts the archive read from `reader` into `dest`, which must not exist
+/// yet.
+///
+/// No entry can be written outside of `dest`, either with an absolute or
+/// `..` path or through a symlink that an earlier entry made. Ownership and
+/// timestamps are not kept: directories get mode 755, files 755 or 644
+/// depending on whether they were executable, and everything gets the same
+/// modification time.
+pub(crate) fn unpack<R: Read + Seek>(
+    format: ArchiveFormat,
+    reader: R,
+    dest: &Path
+) -> Result<(), UnpackError> {
+    fs::create_dir(dest).map_err(io_err(dest))?;
+    match format {
+        ArchiveFormat::Tar => unpack_tar(reader, dest)?,
+        ArchiveFormat::TarGz =>
+            unpack_tar(flate2::read::MultiGzDecoder::new(reader), dest)?,
+        ArchiveFormat::TarXz =>
+            unpack_tar(xz2::read::XzDecoder::new_multi_decoder(reader), dest)?,
+        ArchiveFormat::Zip => unpack_zip(reader, dest)?,
+    }
+    dirs::normalize_mtimes(dest).map_err(io_err(dest))
+}
+
+/// Turns an entry name into a path relative to the unpack directory, or
+/// returns `None` if it could escape it.
+pub(crate) fn safe_path(path: &Path) -> Option<PathBuf> {
+    let mut safe = PathBuf::new();
+    for comp in path.components() {
+        match comp {
+            Component::Normal(c) => safe.push(c),
+            Component::CurDir => (),
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) =>
+                return None,
+        }
+    }
+    Some(safe)
+}
+
+// Makes sure every directory leading up to the entry exists and is a real
+// directory, so that nothing gets written through a symlink.
+fn check_parents(dest: &Path, rel: &Path) -> Result<PathBuf, UnpackError> {
+    let mut cur = dest.to_path_buf();
+    if let Some(parent) = rel.parent() {
+        for comp in parent.components() {
+            cur.push(comp);
+            match fs::symlink_metadata(&cur) {
+                Ok(meta) if meta.is_dir() => (),
+                Ok(_) => return Err(UnpackError::UnsafePathError(rel.into())),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    fs::create_dir(&cur).map_err(io_err(&cur))?;
+                }
+                Err(e) => return Err(UnpackError::IOError{err: e, file: cur}),
+            }
+        }
+    }
+    Ok(dest.join(rel))
+}
+
+// As check_parents, but also clears whatever non-directory was already at the
+// entry's path.
+fn prepare_target(dest: &Path, rel: &Path) -> Result<PathBuf, UnpackError> {
+    let target = check_parents(dest, rel)?;
+    match fs::symlink_metadata(&target) {
+        Ok(meta) if !meta.is_dir() => {
+            fs::remove_file(&target).map_err(io_err(&target))?;
+        }
+        _ => (),
+    }
+    Ok(target)
+}
+
+fn entry_path(name: &Path) -> Result<PathBuf, UnpackError> {
+    safe_path(name).ok_or_else(|| UnpackError::UnsafePathError(name.into()))
+}
+
+fn write_dir(dest: &Path, rel: &Path) -> Result<(), UnpackError> {
+    if rel.as_os_str().is_empty() {
+        return Ok(());
+    }
+    let target = prepare_target(dest, rel)?;
+    if !target.is_dir() {
+        fs::create_dir(&target).map_err(io_err(&target))?;
+    }
+    Ok(())
+}
+
+fn write_file<R: Read>(
+    dest: &Path,
+    rel: &Path,
+    mode: u32,
+    contents: &mut R
+) -> Result<(), UnpackError> {
+    let target = prepare_target(dest, rel)?;
+    let mode = if mode & 0o111 != 0 { 0o755 } else { 0o644 };
+    // create_new also refuses to follow a symlink at the target
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(&target)
+        .map_err(io_err(&target))?;
+    io::copy(contents, &mut file).map_err(io_err(&target))?;
+    Ok(())
+}
+
+fn write_symlink(dest: &Path, rel: &Path, link: &Path) -> Result<(), UnpackError> {
+    let target = prepare_target(dest, rel)?;
+    symlink(link, &target).map_err(io_err(&target))
+}
+
+fn write_hardlink(dest: &Path, rel: &Path, link: &Path) -> Result<(), UnpackError> {
+    let link_rel = entry_path(link)?;
+    let src = check_parents(dest, &link_rel)?;
+    let target = prepare_target(dest, rel)?;
+    fs::hard_link(&src, &target).map_err(io_err(&target))
+}
+
+fn unpack_tar<R: Read>(reader: R, dest: &Path) -> Result<(), UnpackError> {
+    use tar::EntryType;
+
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries().map_err(io_err(dest))? {
+        let mut entry = entry.map_err(io_err(dest))?;
+        let name = entry.path().map_err(io_err(dest))?.into_owned();
+        let rel = entry_path(&name)?;
+        let header = entry.header();
+        let mode = header.mode().map_err(io_err(&name))?;
+        match header.entry_type() {
+            EntryType::Directory => write_dir(dest, &rel)?,
+            EntryType::Regular | EntryType::Continuous =>
+                write_file(dest, &rel, mode, &mut entry)?,
+            EntryType::Symlink | EntryType::Link => {
+                let link = entry.link_name().map_err(io_err(&name))?
+                    .ok_or_else(|| UnpackError::EntryTypeError(name.clone()))?
+                    .into_owned();
+                if entry.header().entry_type() == EntryType::Symlink {
+                    write_symlink(dest, &rel, &link)?;
+                } else {
+                    write_hardlink(dest, &rel, &link)?;
+                }
+            }
+            EntryType::XGlobalHeader => (),
+            _ => return Err(UnpackError::EntryTypeError(name)),
+        }
+    }
+    Ok(())
+}
+
+fn unpack_zip<R: Read + Seek>(reader: R, dest: &Path) -> Result<(), UnpackError> {
+    const S_IFMT: u32 = 0o170000;
+    const S_IFLNK: u32 = 0o120000;
+
+    let mut archive = zip::ZipArchive::new(reader).map_err(UnpackError::ZipError)?;
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i).map_err(UnpackError::ZipError)?;
+        let name = PathBuf::from(file.name());
+        let rel = entry_path(&name)?;
+        let mode = file.unix_mode().unwrap_or(0o644);
+        if file.is_dir() {
+            write_dir(dest, &rel)?;
+        } else if mode & S_IFMT == S_IFLNK {
+            let mut link = String::new();
+            file.read_to_string(&mut link).map_err(io_err(&name))?;
+            write_symlink(dest, &rel, Path::new(&link))?;
+        } else {
+            write_file(dest, &rel, mode, &mut file)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+    use std::os::unix::fs::PermissionsExt;
+    use filetime::FileTime;
+
+    fn append(builder: &mut tar::Builder<Vec<u8>>, name: &[u8], mode: u32, data: &[u8]) {
+        // Write the name by hand, since the tar crate won't let us write an
+        // unsafe one
+        let mut header = tar::Header::new_gnu();
+        header.as_gnu_mut().unwrap().name[..name.len()].copy_from_slice(name);
+        header.set_mode(mode);
+        header.set_size(data.len() as u64);
+        header.set_mtime(1234567890);
+        header.set_cksum();
+        builder.append(&header, data).unwrap();
+    }
+
+    #[test]
+    fn test_unpack_tar() {
+        let mut builder = tar::Builder::new(Vec::new());
+        append(&mut builder, b"pkg/bin/hello", 0o775, b"#!/bin/sh\n");
+        append(&mut builder, b"./pkg/README", 0o600, b"Hello\n");
+        let archive = builder.into_inner().unwrap();
+
+        let dest = env::temp_dir().join(format!("yafpm-unpack-test-{}", process::id()));
+        unpack(ArchiveFormat::Tar, io::Cursor::new(archive), &dest).unwrap();
+        let hello = fs::metadata(dest.join("pkg/bin/hello")).unwrap();
+        assert_eq!(hello.permissions().mode() & 0o777, 0o755);
+        let readme = fs::metadata(dest.join("pkg/README")).unwrap();
+        assert_eq!(readme.permissions().mode() & 0o777, 0o644);
+        assert_eq!(FileTime::from_last_modification_time(&readme).unix_seconds(), 1);
+        assert_eq!(fs::read(dest.join("pkg/README")).unwrap(), b"Hello\n");
+        fs::remove_dir_all(dest).unwrap();
+    }
+
+    #[test]
+    fn test_unpack_zip() {
+        use std::io::Write;
+        use zip::write::{FileOptions, ZipWriter};
+
+        let mut writer = ZipWriter::new(io::Cursor::new(Vec::new()));
+        let options = FileOptions::default();
+        writer.add_directory("pkg/bin", options).unwrap();
+        writer.start_file("pkg/bin/hello", options.unix_permissions(0o775)).unwrap();
+        writer.write_all(b"#!/bin/sh\n").unwrap();
+        writer.start_file("./pkg/README", options.unix_permissions(0o600)).unwrap();
+        writer.write_all(b"Hello\n").unwrap();
+        writer.add_symlink("pkg/link", "README", options).unwrap();
+        let archive = writer.finish().unwrap().into_inner();
+
+        let dest = env::temp_dir().join(format!("yafpm-unpack-zip-test-{}", process::id()));
+        unpack(ArchiveFormat::Zip, io::Cursor::new(archive), &dest).unwrap();
+        let hello = fs::metadata(dest.join("pkg/bin/hello")).unwrap();
+        assert_eq!(hello.permissions().mode() & 0o777, 0o755);
+        let readme = fs::metadata(dest.join("pkg/README")).unwrap();
+        assert_eq!(readme.permissions().mode() & 0o777, 0o644);
+        assert_eq!(fs::read_link(dest.join("pkg/link")).unwrap(), Path::new("README"));
+        assert_eq!(fs::read(dest.join("pkg/link")).unwrap(), b"Hello\n");
+        fs::remove_dir_all(dest).unwrap();
+    }
+
+    #[test]
+    fn test_unpack_traversal() {
+        let mut builder = tar::Builder::new(Vec::new());
+        append(&mut builder, b"../yafpm-unpack-escape", 0o644, b"oops");
+        let archive = builder.into_inner().unwrap();
+
+        let dest = env::temp_dir().join(
+            format!("yafpm-unpack-traversal-test-{}", process::id()));
+        let res = unpack(ArchiveFormat::Tar, io::Cursor::new(archive), &dest);
+        assert!(matches!(res, Err(UnpackError::UnsafePathError(_))));
+        assert!(!env::temp_dir().join("yafpm-unpack-escape").exists());
+        fs::remove_dir_all(dest).unwrap();
+    }
+}
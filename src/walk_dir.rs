@@ -17,15 +17,70 @@
 
 use std::fs;
 use std::io;
+use std::io::{Read, Write};
+use std::convert::TryFrom;
 use std::path::Path;
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
+
+/// Selects how a directory tree is turned into bytes for hashing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "u8", into = "u8"))]
+pub enum DirHashVersion {
+    /// The original scheme: names, file contents and symlink targets run
+    /// together. It ignores permissions and empty directories, and
+    /// different trees can produce the same bytes. Only kept so that
+    /// existing hashes still verify, which is why build files that don't
+    /// give a `hash_version` are taken to use it.
+    V0,
+    /// The canonical archive written by [write_archive], which everything
+    /// new should use.
+    #[default]
+    V1,
+}
+
+impl TryFrom<u8> for DirHashVersion {
+    type Error = String;
+
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        match v {
+            0 => Ok(DirHashVersion::V0),
+            1 => Ok(DirHashVersion::V1),
+            v => Err(format!("unknown directory hash version {}", v)),
+        }
+    }
+}
+
+impl From<DirHashVersion> for u8 {
+    fn from(v: DirHashVersion) -> u8 {
+        match v {
+            DirHashVersion::V0 => 0,
+            DirHashVersion::V1 => 1,
+        }
+    }
+}
 
 pub fn calculate_directory_hash<P: AsRef<Path>, D: io::Write> (
+    dir: P,
+    version: DirHashVersion,
+    hasher: &mut D
+) -> Result<(), io::Error> {
+    match version {
+        DirHashVersion::V0 => legacy_directory_hash(dir, hasher),
+        DirHashVersion::V1 => write_archive(dir, hasher),
+    }
+}
+
+fn legacy_directory_hash<P: AsRef<Path>, D: io::Write> (
     dir: P,
     hasher: &mut D
 ) -> Result<(), io::Error> {
     let mut entries: Vec<_> = fs::read_dir(dir)?.collect::<Result<_, _>>()?;
-    entries.sort_by(|x, y| x.path().cmp(&y.path()));
+    entries.sort_by_key(|x| x.path());
     for entry in entries {
         hasher.write_all(entry.file_name().as_bytes())?;
         if entry.file_type()?.is_file() {
@@ -35,8 +90,120 @@ pub fn calculate_directory_hash<P: AsRef<Path>, D: io::Write> (
             let target = fs::read_link(entry.path())?;
             hasher.write_all(target.as_os_str().as_bytes())?;
         } else if entry.file_type()?.is_dir() {
-            calculate_directory_hash(entry.path(), hasher)?;
+            legacy_directory_hash(entry.path(), hasher)?;
         }
     }
     Ok(())
 }
+
+const ARCHIVE_MAGIC: &[u8] = b"yafpm-archive-1";
+
+// Every field is a little-endian u64 length, then the bytes, then zero
+// padding up to a multiple of 8, so no two fields can run together.
+fn write_field<W: Write>(w: &mut W, bytes: &[u8]) -> Result<(), io::Error> {
+    w.write_all(&(bytes.len() as u64).to_le_bytes())?;
+    w.write_all(bytes)?;
+    write_padding(w, bytes.len() as u64)
+}
+
+fn write_padding<W: Write>(w: &mut W, len: u64) -> Result<(), io::Error> {
+    let pad = (8 - len % 8) % 8;
+    w.write_all(&[0u8; 8][..pad as usize])
+}
+
+fn write_node<W: Write>(path: &Path, w: &mut W) -> Result<(), io::Error> {
+    let meta = fs::symlink_metadata(path)?;
+    let file_type = meta.file_type();
+    write_field(w, b"(")?;
+    write_field(w, b"type")?;
+    if file_type.is_file() {
+        write_field(w, b"regular")?;
+        if meta.permissions().mode() & 0o111 != 0 {
+            write_field(w, b"executable")?;
+        }
+        write_field(w, b"contents")?;
+        let len = meta.len();
+        w.write_all(&len.to_le_bytes())?;
+        let copied = io::copy(&mut fs::File::open(path)?.take(len), w)?;
+        if copied != len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} changed size while being read", path.display())
+            ));
+        }
+        write_padding(w, len)?;
+    } else if file_type.is_symlink() {
+        write_field(w, b"symlink")?;
+        write_field(w, b"target")?;
+        write_field(w, fs::read_link(path)?.as_os_str().as_bytes())?;
+    } else if file_type.is_dir() {
+        write_field(w, b"directory")?;
+        let mut entries: Vec<_> = fs::read_dir(path)?.collect::<Result<_, _>>()?;
+        entries.sort_by_key(|x| x.file_name());
+        for entry in entries {
+            write_field(w, b"entry")?;
+            write_field(w, b"(")?;
+            write_field(w, b"name")?;
+            write_field(w, entry.file_name().as_bytes())?;
+            write_field(w, b"node")?;
+            write_node(&entry.path(), w)?;
+            write_field(w, b")")?;
+        }
+    } else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not a regular file, directory or symlink", path.display())
+        ));
+    }
+    write_field(w, b")")
+}
+
+/// Writes a canonical serialization of the tree at `path`, much like a Nix
+/// archive. Every field is length-prefixed and every node is tagged with its
+/// type, directory entries are sorted by name, regular files record whether
+/// they are executable, and nothing else (timestamps, owners, other
+/// permission bits) is included.
+pub fn write_archive<P: AsRef<Path>, W: Write>(
+    path: P,
+    w: &mut W
+) -> Result<(), io::Error> {
+    write_field(w, ARCHIVE_MAGIC)?;
+    write_node(path.as_ref(), w)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn archive_of(dir: &Path) -> Vec<u8> {
+        let mut buf = Vec::new();
+        write_archive(dir, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_write_archive() {
+        let dir = env::temp_dir().join(format!("yafpm-archive-test-{}", process::id()));
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("a"), b"bc").unwrap();
+        let first = archive_of(&dir);
+        assert_eq!(first.len() % 8, 0);
+
+        fs::remove_file(dir.join("a")).unwrap();
+        fs::write(dir.join("ab"), b"c").unwrap();
+        let second = archive_of(&dir);
+        assert_ne!(first, second);
+
+        let mut perms = fs::metadata(dir.join("ab")).unwrap().permissions();
+        perms.set_mode(0o755);
+        fs::set_permissions(dir.join("ab"), perms).unwrap();
+        let third = archive_of(&dir);
+        assert_ne!(second, third);
+
+        fs::create_dir(dir.join("empty")).unwrap();
+        assert_ne!(third, archive_of(&dir));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
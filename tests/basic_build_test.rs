@@ -1,7 +1,6 @@
 use std::str::FromStr;
-use std::collections::HashMap;
 
-use yafpm::{BuildCxt,Resource,Package};
+use yafpm::{BuildCxt,DirHashVersion,Resource};
 use url::Url;
 use blake2::Blake2s;
 use digest::Digest;
@@ -42,8 +41,9 @@ fn basic_build_test() {
         "0.0",
         GenericArray::clone_from_slice(&output_hash).into(),
         "/unhex",
-        HashMap::new(),
     );
+    // The hash predates hash_version 1
+    cxt.pkg_info.set_hash_version(DirHashVersion::V0);
     cxt.add_srcs([bin, hex]).add_build_cmd_args([
         "/unhex.x",
 "/tmp/unhex-0.0-E3YXKRQTS3Y4XESYAVAW23VXLXEGONLXMRCXZS42QSELBJXINPDA/unhex"
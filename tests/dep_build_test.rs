@@ -1,7 +1,6 @@
 use std::str::FromStr;
-use std::collections::HashMap;
 
-use yafpm::{BuildCxt,Resource,Package};
+use yafpm::{BuildCxt,DirHashVersion,Resource,Package};
 use url::Url;
 use blake2::Blake2s;
 use digest::Digest;
@@ -38,8 +37,9 @@ fn dep_build_test() {
         165, 76, 143, 147, 152, 22, 137, 122, 15, 37, 132, 36, 249, 240, 18,
         8, 250, 216, 171, 86, 55, 247, 244, 47]).into(),
 "/tmp/unhex-0.0-E3YXKRQTS3Y4XESYAVAW23VXLXEGONLXMRCXZS42QSELBJXINPDA/unhex",
-        HashMap::new(),
     );
+    // The hash predates hash_version 1
+    cxt.pkg_info.set_hash_version(DirHashVersion::V0);
     cxt.add_srcs([elfify]).add_build_deps([unhex]).add_build_cmd_args([
         "/elfify.x",
 "/tmp/elfify-0.0-DUUBIMXEVSELLJKMR6JZQFUJPIHSLBBE7HYBECH23CVVMN7X6QXQ/elfify"
@@ -0,0 +1,101 @@
+use std::convert::TryFrom;
+use std::str::FromStr;
+use std::process::Command;
+
+use yafpm::{BuildCxt,DirHashVersion,ItemHash,Package,Resource};
+use url::Url;
+use blake2::Blake2s;
+use digest::Digest;
+use digest::generic_array::GenericArray;
+
+fn mkout_cxt(hash: ItemHash) -> BuildCxt<'static> {
+    let bin_full_url = concat!(
+        "file://",
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/pkgs/mkout");
+    let bin_bytes = include_bytes!("pkgs/mkout");
+    let bin_hash = Blake2s::digest(bin_bytes);
+    let bin = Resource::new(
+        "mkout",
+        bin_hash.into(),
+        Url::from_str(bin_full_url).unwrap()
+    );
+    let mut cxt = BuildCxt::new("mkout", "0.0", hash, "/mkout");
+    cxt.add_srcs([bin]);
+    cxt
+}
+
+// Prints the hash of mkout as yafpm-build --print-hash would, along with the
+// hash version, installing it too if `install` is set
+fn discover(store: &str, install: bool) {
+    let cxt = mkout_cxt(GenericArray::clone_from_slice(&[0; 32]).into());
+    let report = cxt.discover_hash(store, install).unwrap();
+    println!("{} {}", report.pkg_info.hash(), u8::from(report.pkg_info.hash_version()));
+}
+
+fn build(store: &str, hash: &str, version: &str) {
+    let mut cxt = mkout_cxt(ItemHash::from_str(hash).unwrap());
+    let version = DirHashVersion::try_from(version.parse::<u8>().unwrap()).unwrap();
+    cxt.pkg_info.set_hash_version(version);
+    cxt.exec_build(store).unwrap();
+}
+
+// Discovers the hash of mkout, whose output doesn't depend on where it is,
+// and then builds it again with the hash that was printed, which has to
+// verify. A process stays in the namespaces of the build it sets up, so each
+// build runs in a process of its own.
+fn discover_test() {
+    let store = std::env::temp_dir().join(
+        format!("yafpm-discover-test-{}", std::process::id()));
+    std::fs::create_dir(&store).unwrap();
+    let this_exe = std::env::current_exe().unwrap();
+
+    let output = Command::new(&this_exe).arg("discover").arg(&store)
+        .output().unwrap();
+    assert!(output.status.success());
+    let printed = String::from_utf8(output.stdout).unwrap();
+    let (hash, version) = printed.trim_end().split_once(' ').unwrap();
+    assert_eq!(version, "1");
+    let pkg = Package::new("mkout", "0.0", ItemHash::from_str(hash).unwrap());
+    let out_dir = store.join(pkg.pkg_ident());
+    assert!(!out_dir.exists());
+
+    let status = Command::new(&this_exe).arg("build").arg(&store)
+        .arg(hash).arg(version)
+        .status().unwrap();
+    assert!(status.success());
+    assert_eq!(std::fs::read(out_dir.join("file")).unwrap(), b"discovered\n");
+
+    // Installing what is discovered keeps what is already installed, as
+    // long as it's what was built
+    let file = out_dir.join("file");
+    Command::new("chmod").arg("-R").arg("u+w").arg(&out_dir).status().unwrap();
+    std::fs::write(&file, b"tampered\n").unwrap();
+    let output = Command::new(&this_exe).arg("install").arg(&store)
+        .output().unwrap();
+    assert!(!output.status.success());
+    assert_eq!(std::fs::read(&file).unwrap(), b"tampered\n");
+    std::fs::write(&file, b"discovered\n").unwrap();
+    Command::new("chmod").arg("-R").arg("a-w").arg(&out_dir).status().unwrap();
+    let status = Command::new(&this_exe).arg("install").arg(&store)
+        .status().unwrap();
+    assert!(status.success());
+
+    Command::new("chmod").arg("-R").arg("u+w").arg(&store).status().unwrap();
+    std::fs::remove_dir_all(&store).unwrap();
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match &args[..] {
+        [cmd, store] if cmd == "discover" => discover(store, false),
+        [cmd, store] if cmd == "install" => discover(store, true),
+        [cmd, store, hash, version] if cmd == "build" => build(store, hash, version),
+        _ => {
+            println!();
+            print!("test test_mount::discover_test ... ");
+            discover_test();
+            println!("ok");
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use std::str::FromStr;
+use std::fs::Permissions;
+use std::os::unix::fs::PermissionsExt;
+
+use yafpm::{BuildCxt,Resource,Package};
+use url::Url;
+use blake2::Blake2s;
+use digest::Digest;
+use digest::generic_array::GenericArray;
+use data_encoding::HEXLOWER;
+
+// Builds with unhex as a dependency, but has unhex write elfify over the
+// dependency's own binary. The binary is made writable first, as a build
+// script could do itself since it runs as the owner of the store. This relies
+// on basic_build_test having installed unhex already.
+fn ro_dep_test() {
+    let bin_full_url = concat!(
+        "file://",
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/pkgs/unhex");
+    let bin_bytes = include_bytes!("pkgs/unhex");
+    let bin_hash = Blake2s::digest(bin_bytes);
+    let bin = Resource::new(
+        "unhex",
+        bin_hash.into(),
+        Url::from_str(bin_full_url).unwrap()
+    );
+
+    let elfify_full_url = concat!(
+        "file://",
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/pkgs/elfify.x");
+    let elfify_bytes = include_bytes!("pkgs/elfify.x");
+    let elfify_hash = Blake2s::digest(elfify_bytes);
+    let elfify = Resource::new(
+        "elfify.x",
+        elfify_hash.into(),
+        Url::from_str(elfify_full_url).unwrap()
+    );
+
+    let unhex_hash = HEXLOWER.decode(
+        b"26f175461396f1cb925805416d6eb75dc867357764457ccb9a8488b0a6e86bc6"
+    ).unwrap();
+    let unhex = Package::new(
+        "unhex",
+        "0.0",
+        GenericArray::clone_from_slice(&unhex_hash).into()
+    );
+
+    let temp_dir = std::env::temp_dir();
+    let dep_bin = temp_dir.join(unhex.pkg_ident()).join("unhex");
+    let mut cxt = BuildCxt::new(
+        "clobber",
+        "0.0",
+        GenericArray::clone_from_slice(&[0; 32]).into(),
+        "/unhex",
+    );
+    cxt.add_srcs([bin, elfify]).add_build_deps([unhex]).add_build_cmd_args([
+        "/elfify.x",
+"/tmp/unhex-0.0-E3YXKRQTS3Y4XESYAVAW23VXLXEGONLXMRCXZS42QSELBJXINPDA/unhex"
+    ]);
+    let mode = std::fs::metadata(&dep_bin).unwrap().permissions();
+    std::fs::set_permissions(&dep_bin, Permissions::from_mode(0o755)).unwrap();
+    let out_dir = temp_dir.join(cxt.pkg_info.pkg_ident());
+    let res = cxt.exec_build(temp_dir.as_os_str());
+    std::fs::set_permissions(&dep_bin, mode).unwrap();
+    assert!(res.is_err());
+    // A failed build leaves nothing in the store
+    assert!(!out_dir.exists());
+    let dep_bytes = std::fs::read(&dep_bin).unwrap();
+    assert_eq!(Blake2s::digest(&dep_bytes), bin_hash);
+}
+
+fn main() {
+    println!();
+    print!("test test_mount::ro_dep_test ... ");
+    ro_dep_test();
+    println!("ok");
+}